@@ -0,0 +1,141 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::punctuated::Punctuated;
+use syn::{Data, DeriveInput, Fields, LitStr, Token, parenthesized, parse_macro_input};
+
+/// Generates the metric registration and label binding of a struct from its definition, so the label names and the values passed to
+/// `with_label_values` can never drift apart.
+///
+/// Struct attributes:
+/// - `#[metric(name = "...", help = "...")]`: Generates `register()`. Without them only the label binding is generated.
+/// - `#[metric(extra_labels("user_name", "user_id"))]`: Labels that are not part of the struct, they are passed to `set_metric` and come first.
+///
+/// Field attributes:
+/// - `#[metric(label)]` / `#[metric(label = "other_name")]`: Exports the field as a label, formatted via `crate::metrics::LabelValue` and passed
+///   through `crate::privacy::apply`, as are the extra labels.
+/// - `#[metric(privacy = "device_name")]`: The privacy rule applied to the label, if it differs from the label name. All rule names end up in `PRIVACY_LABELS`.
+/// - `#[metric(value)]`: Uses the field as the gauge value instead of `1`.
+#[proc_macro_derive(JellyfinMetric, attributes(metric))]
+pub fn derive_jellyfin_metric(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut name = None;
+    let mut help = None;
+    let mut extra_labels = Vec::<LitStr>::new();
+
+    for attr in input.attrs.iter().filter(|it| it.path().is_ident("metric")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                name = Some(meta.value()?.parse::<LitStr>()?);
+            } else if meta.path.is_ident("help") {
+                help = Some(meta.value()?.parse::<LitStr>()?);
+            } else if meta.path.is_ident("extra_labels") {
+                let content;
+                parenthesized!(content in meta.input);
+                extra_labels.extend(Punctuated::<LitStr, Token![,]>::parse_terminated(&content)?);
+            } else {
+                return Err(meta.error("expected `name`, `help` or `extra_labels`"));
+            }
+
+            Ok(())
+        })?;
+    }
+
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(&input.ident, "JellyfinMetric can only be derived for structs"));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(&input.ident, "JellyfinMetric requires named fields"));
+    };
+
+    let mut labels = Vec::new();
+    let mut value = None;
+
+    for field in &fields.named {
+        let ident = field.ident.clone().unwrap();
+        let mut privacy = None;
+
+        for attr in field.attrs.iter().filter(|it| it.path().is_ident("metric")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("label") {
+                    let label = if meta.input.peek(Token![=]) { meta.value()?.parse::<LitStr>()? } else { LitStr::new(&ident.to_string(), ident.span()) };
+                    labels.push((ident.clone(), label.clone(), label));
+                } else if meta.path.is_ident("value") {
+                    if value.is_some() {
+                        return Err(meta.error("only one field can be the value"));
+                    }
+                    value = Some(ident.clone());
+                } else if meta.path.is_ident("privacy") {
+                    privacy = Some(meta.value()?.parse::<LitStr>()?);
+                } else {
+                    return Err(meta.error("expected `label`, `value` or `privacy`"));
+                }
+
+                Ok(())
+            })?;
+        }
+
+        if let Some(privacy) = privacy {
+            match labels.last_mut() {
+                Some(label) if label.0 == ident => label.2 = privacy,
+                _ => return Err(syn::Error::new_spanned(&ident, "`privacy` requires the field to be a label")),
+            }
+        }
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let num_labels = labels.len();
+    let num_extra_labels = extra_labels.len();
+    let label_names = labels.iter().map(|(_, label, _)| label);
+    let privacy_names = labels.iter().map(|(_, _, privacy)| privacy);
+    let privacy_labels = privacy_names.clone();
+    let label_fields = labels.iter().map(|(field, _, _)| field);
+
+    let own_indices = (0..num_labels).map(syn::Index::from);
+    let extra_indices = (0..num_extra_labels).map(syn::Index::from);
+
+    let value = match value {
+        Some(field) => quote! { ::std::convert::Into::<i64>::into(self.#field) },
+        None => quote! { 1 },
+    };
+
+    let (extra_param, extra_values) = match num_extra_labels {
+        0 => (quote! {}, quote! {}),
+        _ => (quote! { , extra: [&str; #num_extra_labels] }, quote! { #(&crate::privacy::apply(#extra_labels, extra[#extra_indices]),)* }),
+    };
+
+    let register = match (name, help) {
+        (Some(name), Some(help)) => quote! {
+            pub fn register() -> ::prometheus_exporter::prometheus::IntGaugeVec {
+                ::prometheus_exporter::prometheus::register_int_gauge_vec!(#name, #help, Self::LABELS).unwrap()
+            }
+        },
+        (None, None) => quote! {},
+        _ => return Err(syn::Error::new_spanned(ident, "`name` and `help` have to be specified together")),
+    };
+
+    Ok(quote! {
+        impl #impl_generics #ident #ty_generics #where_clause {
+            pub const LABELS: &'static [&'static str] = &[#(#extra_labels,)* #(#label_names,)*];
+
+            /// The names the privacy rules of the labels are looked up by
+            pub const PRIVACY_LABELS: &'static [&'static str] = &[#(#extra_labels,)* #(#privacy_labels,)*];
+
+            pub fn label_values(&self) -> [String; #num_labels] {
+                [#(crate::privacy::apply(#privacy_names, &crate::metrics::LabelValue::label_value(&self.#label_fields)).into_owned(),)*]
+            }
+
+            #register
+
+            pub fn set_metric(&self, gauge: &::prometheus_exporter::prometheus::IntGaugeVec #extra_param) {
+                let values = self.label_values();
+                gauge.with_label_values(&[#extra_values #(values[#own_indices].as_str(),)*]).set(#value);
+            }
+        }
+    })
+}
@@ -1,9 +1,14 @@
+use crate::circuit_breaker;
 use crate::cli::Cli;
+use crate::credentials::{Secret, access_token, reauthenticate};
+use crate::http_client::{endpoint, headers};
 use crate::metrics::{Device, Item, ItemCounts, JellyfinConfig, Metrics, Session, User};
 use futures::StreamExt;
 use log::warn;
-use reqwest::{Client, Response};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
 // TODO:
 // - Sessions
 //   - Active / Total
@@ -19,36 +24,130 @@ use serde::{Deserialize, Serialize};
 // → each exported item has to be associated with the library and user
 // How much total runtime each show has, how many episodes etc
 
-pub async fn make_api_get_call(cli: &Cli, client: &Client, path: &str) -> Result<Response, reqwest::Error> {
-    client.get(cli.jellyfin_address.clone().join(path).unwrap()).send().await
+pub async fn make_api_get_call(cli: &Cli, client: &Client, path: &str, query: &[(&str, &str)]) -> Result<Response, reqwest::Error> {
+    let url = endpoint(cli, path, query);
+    send_authenticated(cli, client, || client.get(url.clone())).await
 }
 
-pub async fn make_api_post_call(cli: &Cli, client: &Client, path: &str) -> Result<Response, reqwest::Error> {
-    client.post(cli.jellyfin_address.clone().join(path).unwrap()).send().await
+/// `timeout` replaces the `--jellyfin-request-timeout` of the client, e.g. to finish before the deadline of a scrape
+pub async fn make_api_post_call(cli: &Cli, client: &Client, path: &str, timeout: Option<Duration>) -> Result<Response, reqwest::Error> {
+    let url = endpoint(cli, path, &[]);
+    send_authenticated(cli, client, || match timeout {
+        Some(it) => client.post(url.clone()).timeout(it),
+        None => client.post(url.clone()),
+    })
+    .await
 }
 
-pub async fn get_users(cli: &Cli, client: &Client) -> Result<Vec<User>, reqwest::Error> {
-    make_api_get_call(cli, client, "/Users").await?.json().await
+/// GETs are idempotent, so timeouts, connection errors, 429 and 5xx responses are retried up to `--jellyfin-retries` times with an exponential backoff.
+/// The response body is read within the retried part, as a timeout of a large `/Items` response usually happens while reading it.
+/// Every attempt is recorded by the circuit breaker, an open breaker stops the retries.
+pub async fn get_json<T: DeserializeOwned>(cli: &Cli, client: &Client, metrics: &Metrics, path: &str, query: &[(&str, &str)]) -> Result<T, reqwest::Error> {
+    let mut attempt = 0;
+
+    loop {
+        let s = Instant::now();
+        let result = async { make_api_get_call(cli, client, path, query).await?.error_for_status()?.json::<T>().await }.await;
+        circuit_breaker::record(cli, metrics, path, s.elapsed(), result.is_ok());
+
+        let e = match result {
+            Ok(it) => return Ok(it),
+            Err(e) => e,
+        };
+
+        count_timeout(metrics, path, &e);
+        if attempt >= cli.jellyfin_retries || !is_retryable(&e) || circuit_breaker::is_open() {
+            return Err(e);
+        }
+
+        let delay = Duration::from_millis(cli.jellyfin_retry_backoff).saturating_mul(2u32.saturating_pow(attempt));
+        warn!("Request to {} failed, retrying in {:?}: {}", path, delay, e);
+        metrics.jellyfin_exporter_http_retries.with_label_values(&[path]).inc();
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
 }
 
-pub async fn get_sessions(cli: &Cli, client: &Client) -> Result<Vec<Session>, reqwest::Error> {
-    make_api_get_call(cli, client, "/Sessions").await?.json().await
+fn is_retryable(e: &reqwest::Error) -> bool {
+    e.is_timeout() || e.is_connect() || e.is_body() || e.status().is_some_and(|it| it.is_server_error() || it == StatusCode::TOO_MANY_REQUESTS)
 }
 
-pub async fn get_jellyfin_config(cli: &Cli, client: &Client) -> Result<JellyfinConfig, reqwest::Error> {
-    make_api_get_call(cli, client, "/System/Info").await?.json().await
+fn count_timeout(metrics: &Metrics, path: &str, e: &reqwest::Error) {
+    if e.is_timeout() {
+        metrics.jellyfin_exporter_http_timeouts.with_label_values(&[path]).inc();
+    }
 }
 
-pub async fn get_devices(cli: &Cli, client: &Client) -> Result<Vec<Device>, reqwest::Error> {
-    Ok(make_api_get_call(cli, client, "/Devices").await?.json::<ItemResponse<Device>>().await?.items)
+/// Sends the request with the current token. When logged in with a username and password, an expired token is renewed and the request is sent again.
+async fn send_authenticated(cli: &Cli, client: &Client, request: impl Fn() -> RequestBuilder) -> Result<Response, reqwest::Error> {
+    let token = access_token();
+    let response = request().headers(headers(cli)).send().await?;
+
+    if response.status() != StatusCode::UNAUTHORIZED || cli.jellyfin_username.is_none() {
+        return Ok(response);
+    }
+
+    if !reauthenticate(cli, client, &token).await? {
+        return Ok(response);
+    }
+    request().headers(headers(cli)).send().await
 }
 
-pub async fn get_item_counts(cli: &Cli, client: &Client) -> Result<ItemCounts, reqwest::Error> {
-    make_api_get_call(cli, client, "/Items/Counts").await?.json().await
+// No `Debug`, these must never be logged
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct AuthenticateByName<'a> {
+    username: &'a str,
+    pw: &'a str,
 }
 
-pub async fn get_jellyfin_up(cli: &Cli, client: &Client) -> Result<String, reqwest::Error> {
-    make_api_post_call(cli, client, "/System/Ping").await?.json().await
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AuthenticationResult {
+    access_token: String,
+}
+
+pub async fn authenticate_by_name(cli: &Cli, client: &Client, username: &str, password: &Secret) -> Result<Secret, reqwest::Error> {
+    let response = client
+        .post(endpoint(cli, "/Users/AuthenticateByName", &[]))
+        .headers(headers(cli))
+        .json(&AuthenticateByName { username, pw: password.expose() })
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(response.json::<AuthenticationResult>().await?.access_token.into())
+}
+
+pub async fn get_users(cli: &Cli, client: &Client, metrics: &Metrics) -> Result<Vec<User>, reqwest::Error> {
+    get_json(cli, client, metrics, "/Users", &[]).await
+}
+
+pub async fn get_sessions(cli: &Cli, client: &Client, metrics: &Metrics) -> Result<Vec<Session>, reqwest::Error> {
+    get_json(cli, client, metrics, "/Sessions", &[]).await
+}
+
+pub async fn get_jellyfin_config(cli: &Cli, client: &Client, metrics: &Metrics) -> Result<JellyfinConfig, reqwest::Error> {
+    get_json(cli, client, metrics, "/System/Info", &[]).await
+}
+
+pub async fn get_devices(cli: &Cli, client: &Client, metrics: &Metrics) -> Result<Vec<Device>, reqwest::Error> {
+    Ok(get_json::<ItemResponse<Device>>(cli, client, metrics, "/Devices", &[]).await?.items)
+}
+
+pub async fn get_item_counts(cli: &Cli, client: &Client, metrics: &Metrics) -> Result<ItemCounts, reqwest::Error> {
+    get_json(cli, client, metrics, "/Items/Counts", &[]).await
+}
+
+// A POST, so it is not retried. A down server is reported on the next scrape instead.
+pub async fn get_jellyfin_up(cli: &Cli, client: &Client, metrics: &Metrics, timeout: Option<Duration>) -> Result<String, reqwest::Error> {
+    let result = async { make_api_post_call(cli, client, "/System/Ping", timeout).await?.json().await }.await;
+    if let Err(e) = &result {
+        count_timeout(metrics, "/System/Ping", e);
+    }
+
+    result
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -65,21 +164,13 @@ struct ItemResponse<T> {
 /// 2. Performance: Jellyfin is able to parallelize multiple API requests.
 ///
 /// TODO: Optimize the memory layout: currently megabytes of memory are allocated and thrown away
-pub async fn get_items<'a>(cli: &Cli, client: &Client, users: Vec<User>, metrics: &mut Metrics) -> Vec<(User, Vec<Item>)> {
+pub async fn get_items(cli: &Cli, client: &Client, metrics: &Metrics, users: Vec<User>) -> Vec<(User, Vec<Item>)> {
     futures::stream::iter(users.into_iter().map(|user| async move {
-        let response = match make_api_get_call(cli, client, &format!("/Items?UserId={}&recursive={}", user.id, !cli.jellyfin_exporter_disable_recursive_item_search)).await {
-            Ok(it) => it,
-            Err(e) => {
-                warn!("Could not fetch user {}: {:?}", user.name, e);
-                return (user, Vec::new());
-            }
-        };
-
-        match response.json::<ItemResponse<Item>>().await {
+        match get_json::<ItemResponse<Item>>(cli, client, metrics, "/Items", &[("UserId", &user.id), ("recursive", "true")]).await {
             Ok(it) => (user, it.items),
             Err(e) => {
-                warn!("Could not decode item data for user {}: {:?}", user.name, e);
-                return (user, Vec::new());
+                warn!("Could not fetch the items of user {}: {:?}", user.name, e);
+                (user, Vec::new())
             }
         }
     }))
@@ -92,16 +183,14 @@ pub async fn get_items<'a>(cli: &Cli, client: &Client, users: Vec<User>, metrics
 pub fn validate_items(items: &Vec<Item>) -> bool {
     for item in items {
         match item {
-            Item::CollectionFolder(it) => {}
-            Item::Series(it) => {}
-            Item::Movie(it) => {}
-            Item::Book(it) => {}
-            Item::Season(it) => {}
-            Item::Episode(it) => {
-                if it.series_name.is_none() || it.series_id.is_none() {
-                    warn!("The episode \"{}\" ({}) does not have a series attached - this is probably a movie!", it.name, it.id);
-                    return false;
-                }
+            Item::CollectionFolder(_) => {}
+            Item::Series(_) => {}
+            Item::Movie(_) => {}
+            Item::Book(_) => {}
+            Item::Season(_) => {}
+            Item::Episode(it) if it.series_name.is_none() || it.series_id.is_none() => {
+                warn!("The episode \"{}\" ({}) does not have a series attached - this is probably a movie!", it.name, it.id);
+                return false;
             }
             _ => {}
         }
@@ -0,0 +1,193 @@
+use crate::metrics::Metrics;
+use log::{debug, warn};
+use prometheus_exporter::prometheus::IntCounterVec;
+use prometheus_exporter::prometheus::core::{Collector, MetricVec, MetricVecBuilder};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+
+// The metrics of the `info` layout are joined on this label, so they have to keep the same items
+const ITEM_LABEL: &str = "item_id";
+
+// The label values of the counter series that were exported, per family
+static EXPORTED: Mutex<BTreeMap<String, HashSet<Vec<String>>>> = Mutex::new(BTreeMap::new());
+// The families that were already reported as too large
+static WARNED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Caps the number of series a single metric family may export. Excess series are dropped and counted in `jellyfin_exporter_series_dropped_total`.
+///
+/// Series are sorted by their label values before the cut-off, so the same subset survives between scrapes as long as the library does not change.
+/// The `item_id` label is compared first, and with `items` only series of these items are kept, see `surviving_items`.
+pub fn limit_series<T: MetricVecBuilder>(vec: &MetricVec<T>, limit: usize, dropped: &IntCounterVec, items: Option<&HashSet<String>>) {
+    cut_series(vec, limit, dropped, items, false);
+}
+
+/// Like `limit_series`, but the series exported before always survive and only new ones are dropped once the family is at the limit.
+/// A dropped and later re-added counter series would start from 0 again, which looks like a reset to `rate()`.
+pub fn limit_counter_series<T: MetricVecBuilder>(vec: &MetricVec<T>, limit: usize, dropped: &IntCounterVec) {
+    cut_series(vec, limit, dropped, None, true);
+}
+
+fn cut_series<T: MetricVecBuilder>(vec: &MetricVec<T>, limit: usize, dropped: &IntCounterVec, items: Option<&HashSet<String>>, keep_exported: bool) {
+    for family in vec.collect() {
+        let metrics = family.get_metric();
+        let mut exported = EXPORTED.lock().unwrap();
+        let mut exported = keep_exported.then(|| exported.entry(family.get_name().to_string()).or_default());
+
+        let label_sets = metrics.iter().map(|it| it.get_label().iter().map(|it| (it.get_name(), it.get_value())).collect::<Vec<_>>()).collect::<Vec<_>>();
+        let (mut label_sets, mut excess): (Vec<_>, Vec<_>) = label_sets.into_iter().partition(|labels| match (items, item_id(labels)) {
+            (Some(items), Some(id)) => items.contains(id),
+            _ => true,
+        });
+
+        if label_sets.len() > limit {
+            let is_new = |labels: &[(&str, &str)]| exported.as_ref().is_some_and(|it| !it.contains(&values(labels)));
+            label_sets.sort_by(|a, b| is_new(a).cmp(&is_new(b)).then_with(|| item_id(a).cmp(&item_id(b))).then_with(|| a.iter().map(|it| it.1).cmp(b.iter().map(|it| it.1))));
+            excess.extend(label_sets.drain(limit..));
+        }
+        if let Some(exported) = &mut exported {
+            exported.extend(label_sets.iter().map(|it| values(it)));
+        }
+        if excess.is_empty() {
+            continue;
+        }
+
+        for labels in &excess {
+            if let Err(e) = vec.remove(&labels.iter().copied().collect::<HashMap<_, _>>()) {
+                warn!("Could not drop series of {}: {:?}", family.get_name(), e);
+            }
+        }
+
+        // A family that is too large usually stays too large, so only the first time is worth a warning
+        let message = format!("The metric {} has {} series, dropped {} to stay within the limit of {}", family.get_name(), metrics.len(), excess.len(), limit);
+        if WARNED.lock().unwrap().insert(family.get_name().to_string()) {
+            warn!("{}", message);
+        } else {
+            debug!("{}", message);
+        }
+        dropped.with_label_values(&[family.get_name()]).inc_by(excess.len() as u64);
+    }
+}
+
+fn values(labels: &[(&str, &str)]) -> Vec<String> {
+    labels.iter().map(|it| it.1.to_string()).collect()
+}
+
+fn item_id<'a>(labels: &[(&str, &'a str)]) -> Option<&'a str> {
+    labels.iter().find(|it| it.0 == ITEM_LABEL).map(|it| it.1)
+}
+
+fn item_ids<T: MetricVecBuilder>(vec: &MetricVec<T>, ids: &mut BTreeSet<String>) {
+    for family in vec.collect() {
+        for metric in family.get_metric() {
+            ids.extend(metric.get_label().iter().filter(|it| it.get_name() == ITEM_LABEL).map(|it| it.get_value().to_string()));
+        }
+    }
+}
+
+/// The items that keep their series, picked once for all item metrics: The first `limit` item_ids in sort order, `None` if all fit
+fn surviving_items(metrics: &Metrics, limit: usize) -> Option<HashSet<String>> {
+    let mut ids = BTreeSet::new();
+
+    item_ids(&metrics.jellyfin_item_info, &mut ids);
+    item_ids(&metrics.jellyfin_item_production_year, &mut ids);
+    item_ids(&metrics.jellyfin_item_community_rating, &mut ids);
+    item_ids(&metrics.jellyfin_item_index_number, &mut ids);
+    item_ids(&metrics.jellyfin_item_runtime_seconds, &mut ids);
+    item_ids(&metrics.jellyfin_item_user_play_count, &mut ids);
+    item_ids(&metrics.jellyfin_item_user_played, &mut ids);
+    item_ids(&metrics.jellyfin_item_user_favorite, &mut ids);
+    item_ids(&metrics.jellyfin_item_user_unplayed_item_count, &mut ids);
+    item_ids(&metrics.jellyfin_item_user_played_percentage, &mut ids);
+    item_ids(&metrics.jellyfin_item_user_last_played_timestamp_seconds, &mut ids);
+
+    (ids.len() > limit).then(|| ids.into_iter().take(limit).collect())
+}
+
+pub fn limit_all_series(metrics: &Metrics, limit: usize) {
+    let dropped = &metrics.jellyfin_exporter_series_dropped;
+    let items = surviving_items(metrics, limit);
+    let items = items.as_ref();
+
+    limit_series(&metrics.jellyfin_users, limit, dropped, items);
+    limit_series(&metrics.jellyfin_sessions, limit, dropped, items);
+    limit_series(&metrics.jellyfin_devices, limit, dropped, items);
+
+    limit_series(&metrics.jellyfin_items_library, limit, dropped, items);
+    limit_series(&metrics.jellyfin_items_library_user_data, limit, dropped, items);
+    limit_series(&metrics.jellyfin_items_media_item, limit, dropped, items);
+    limit_series(&metrics.jellyfin_items_media_item_user_data, limit, dropped, items);
+    limit_series(&metrics.jellyfin_items_season, limit, dropped, items);
+    limit_series(&metrics.jellyfin_items_season_user_data, limit, dropped, items);
+    limit_series(&metrics.jellyfin_items_episode, limit, dropped, items);
+    limit_series(&metrics.jellyfin_items_episode_user_data, limit, dropped, items);
+
+    limit_series(&metrics.jellyfin_series_seasons, limit, dropped, items);
+    limit_series(&metrics.jellyfin_series_episodes, limit, dropped, items);
+    limit_series(&metrics.jellyfin_series_runtime_seconds, limit, dropped, items);
+    limit_series(&metrics.jellyfin_series_episodes_watched, limit, dropped, items);
+    limit_series(&metrics.jellyfin_series_completion_ratio, limit, dropped, items);
+
+    limit_series(&metrics.jellyfin_item_info, limit, dropped, items);
+    limit_series(&metrics.jellyfin_item_production_year, limit, dropped, items);
+    limit_series(&metrics.jellyfin_item_community_rating, limit, dropped, items);
+    limit_series(&metrics.jellyfin_item_index_number, limit, dropped, items);
+    limit_series(&metrics.jellyfin_item_runtime_seconds, limit, dropped, items);
+    limit_series(&metrics.jellyfin_item_user_play_count, limit, dropped, items);
+    limit_series(&metrics.jellyfin_item_user_played, limit, dropped, items);
+    limit_series(&metrics.jellyfin_item_user_favorite, limit, dropped, items);
+    limit_series(&metrics.jellyfin_item_user_unplayed_item_count, limit, dropped, items);
+    limit_series(&metrics.jellyfin_item_user_played_percentage, limit, dropped, items);
+    limit_series(&metrics.jellyfin_item_user_last_played_timestamp_seconds, limit, dropped, items);
+
+    limit_series(&metrics.jellyfin_transcode_speed, limit, dropped, items);
+    limit_series(&metrics.jellyfin_session_location, limit, dropped, items);
+    limit_series(&metrics.jellyfin_device_location, limit, dropped, items);
+
+    limit_counter_series(&metrics.jellyfin_playback_seconds, limit, dropped);
+    limit_counter_series(&metrics.jellyfin_playback_starts, limit, dropped);
+    limit_counter_series(&metrics.jellyfin_playback_stops, limit, dropped);
+    limit_counter_series(&metrics.jellyfin_playback_stalls, limit, dropped);
+    limit_counter_series(&metrics.jellyfin_webhook_events, limit, dropped);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus_exporter::prometheus::Opts;
+
+    fn clients(vec: &IntCounterVec) -> Vec<String> {
+        let mut clients = vec.collect()[0].get_metric().iter().map(|it| it.get_label()[0].get_value().to_string()).collect::<Vec<_>>();
+        clients.sort();
+        clients
+    }
+
+    #[test]
+    fn counters_keep_their_exported_series() {
+        let counters = IntCounterVec::new(Opts::new("test_counter_limit_total", "test"), &["client"]).unwrap();
+        let dropped = IntCounterVec::new(Opts::new("test_dropped_total", "test"), &["metric"]).unwrap();
+
+        counters.with_label_values(&["b"]).inc();
+        counters.with_label_values(&["c"]).inc();
+        limit_counter_series(&counters, 2, &dropped);
+
+        // `a` sorts first, but would reset `b` or `c`
+        counters.with_label_values(&["a"]).inc();
+        limit_counter_series(&counters, 2, &dropped);
+
+        assert_eq!(clients(&counters), ["b", "c"]);
+        assert_eq!(dropped.with_label_values(&["test_counter_limit_total"]).get(), 1);
+    }
+
+    #[test]
+    fn keeps_the_first_series_in_sort_order() {
+        let series = IntCounterVec::new(Opts::new("test_series_limit_total", "test"), &["client"]).unwrap();
+        let dropped = IntCounterVec::new(Opts::new("test_dropped_total", "test"), &["metric"]).unwrap();
+
+        for it in ["b", "c", "a"] {
+            series.with_label_values(&[it]).inc();
+        }
+        limit_series(&series, 2, &dropped, None);
+
+        assert_eq!(clients(&series), ["a", "b"]);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::cli::Cli;
+use crate::metrics::Metrics;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Every collector runs
+    Closed,
+    /// Jellyfin is struggling, expensive collectors are skipped and their last values are exported
+    Open,
+    /// The cooldown passed, the next scrape runs all collectors as a trial
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub const ALL: [BreakerState; 3] = [BreakerState::Closed, BreakerState::Open, BreakerState::HalfOpen];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+struct Breaker {
+    state: BreakerState,
+    // Consecutive failed or slow requests per endpoint while closed. Per endpoint, as the cheap endpoints answer quickly even while
+    // the `/Items` crawl is slow, and would reset a shared count on every scrape.
+    failures: BTreeMap<String, u32>,
+    opened: Option<Instant>,
+}
+
+// Shared by all requests, like the access token
+static BREAKER: Mutex<Breaker> = Mutex::new(Breaker { state: BreakerState::Closed, failures: BTreeMap::new(), opened: None });
+
+/// Records the outcome of a request to Jellyfin. A request slower than `--jellyfin-exporter-circuit-breaker-latency` counts as failed,
+/// as a server in the middle of a library scan usually answers slowly instead of not at all.
+pub fn record(cli: &Cli, metrics: &Metrics, path: &str, latency: Duration, success: bool) {
+    if cli.jellyfin_exporter_circuit_breaker_failures == 0 {
+        return;
+    }
+
+    let failed = !success || latency > Duration::from_secs(cli.jellyfin_exporter_circuit_breaker_latency);
+    let mut breaker = BREAKER.lock().unwrap();
+
+    match breaker.state {
+        BreakerState::Closed if failed => {
+            let failures = breaker.failures.entry(path.to_string()).or_default();
+            *failures += 1;
+
+            if *failures >= cli.jellyfin_exporter_circuit_breaker_failures {
+                warn!("Opening the circuit breaker after {} failed or slow requests to {}, the last one took {:?}", failures, path, latency);
+                open(&mut breaker, metrics);
+            }
+        }
+        BreakerState::Closed => {
+            breaker.failures.remove(path);
+        }
+        BreakerState::HalfOpen if failed => {
+            warn!("Request to {} failed or took {:?} during the trial, opening the circuit breaker again", path, latency);
+            open(&mut breaker, metrics);
+        }
+        // Requests that were started before the breaker opened don't matter anymore, a half-open breaker closes after the whole trial scrape
+        BreakerState::HalfOpen | BreakerState::Open => {}
+    }
+}
+
+/// A collector cancelled at the scrape deadline never records its requests, which would be slow or failed at this point.
+/// Prometheus' default scrape timeout of 10s is shorter than the default latency threshold, so this is often the only sign of a slow server.
+pub fn record_cancelled(cli: &Cli, metrics: &Metrics, collector: &str, elapsed: Duration) {
+    record(cli, metrics, &format!("collector {collector}"), elapsed, false);
+}
+
+/// Whether expensive collectors may run. Moves an open breaker to half-open once the cooldown passed.
+pub fn allows_expensive(cli: &Cli, metrics: &Metrics) -> bool {
+    let mut breaker = BREAKER.lock().unwrap();
+
+    if breaker.state == BreakerState::Open && breaker.opened.is_some_and(|it| it.elapsed() >= Duration::from_secs(cli.jellyfin_exporter_circuit_breaker_cooldown)) {
+        info!("Circuit breaker cooldown passed, trying the expensive collectors again");
+        set_state(&mut breaker, BreakerState::HalfOpen, metrics);
+    }
+
+    breaker.state != BreakerState::Open
+}
+
+/// Whether failed requests should not be retried, as that would only add load
+pub fn is_open() -> bool {
+    BREAKER.lock().unwrap().state == BreakerState::Open
+}
+
+/// Closes a half-open breaker once a trial scrape finished without failed, slow or cancelled requests, which would have opened it again
+pub fn scrape_finished(metrics: &Metrics) {
+    let mut breaker = BREAKER.lock().unwrap();
+
+    if breaker.state == BreakerState::HalfOpen {
+        info!("Closing the circuit breaker");
+        breaker.failures.clear();
+        set_state(&mut breaker, BreakerState::Closed, metrics);
+    }
+}
+
+fn open(breaker: &mut Breaker, metrics: &Metrics) {
+    breaker.opened = Some(Instant::now());
+    breaker.failures.clear();
+    metrics.jellyfin_exporter_circuit_breaker_opens.inc();
+    set_state(breaker, BreakerState::Open, metrics);
+}
+
+fn set_state(breaker: &mut Breaker, state: BreakerState, metrics: &Metrics) {
+    breaker.state = state;
+    set_breaker_metrics(state, metrics);
+}
+
+pub fn set_breaker_metrics(state: BreakerState, metrics: &Metrics) {
+    for it in BreakerState::ALL {
+        metrics.jellyfin_exporter_circuit_breaker_state.with_label_values(&[it.as_str()]).set((it == state) as i64);
+    }
+}
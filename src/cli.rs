@@ -1,13 +1,19 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use crate::credentials::Secret;
+use crate::privacy::PrivacyRule;
+use ipnet::IpNet;
 use std::env;
-use std::fmt::{Display, Formatter, write};
+use std::fmt::{Display, Formatter};
 use std::net::IpAddr;
+use std::path::PathBuf;
 use url::{ParseError, Url};
 
-// TODO: Add option to not parse library content as this can very CPU / network intensive
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
+    #[arg(long, env = "JELLYFIN_EXPORTER_CONFIG", help = "TOML or YAML (.yaml / .yml) file with the options, e.g. `jellyfin_address = \"https://jellyfin.example.com\"`. Flags and env vars take precedence")]
+    pub config: Option<PathBuf>,
+
     #[arg(long, env, default_value = "0.0.0.0")]
     pub jellyfin_exporter_address: IpAddr,
 
@@ -17,17 +23,200 @@ pub struct Cli {
     #[arg(long, env, default_value = "info", value_parser=parse_loglevel)]
     pub jellyfin_exporter_loglevel: String,
 
-    #[arg(long, env)]
+    #[arg(long, env, help = "Allow plain http to a non-loopback Jellyfin address, which sends the API key unencrypted")]
     pub jellyfin_exporter_insecure: bool,
 
     #[arg(long, env, value_parser=parse_url)]
     pub jellyfin_address: Url,
 
-    #[arg(long, env)]
-    pub jellyfin_api_key: String,
+    #[arg(long, env, help = "PEM bundle of additional CA certificates to trust for the Jellyfin server, e.g. of a private CA")]
+    pub jellyfin_ca_file: Option<PathBuf>,
+
+    #[arg(long, env, requires = "jellyfin_client_key_file", help = "PEM client certificate, for a reverse proxy requiring mutual TLS")]
+    pub jellyfin_client_cert_file: Option<PathBuf>,
+
+    #[arg(long, env, requires = "jellyfin_client_cert_file", help = "PKCS#8 PEM private key of --jellyfin-client-cert-file")]
+    pub jellyfin_client_key_file: Option<PathBuf>,
+
+    #[arg(long, env, help = "Do not verify the certificate and hostname of the Jellyfin server. Only meant for testing, the connection is open to interception")]
+    pub jellyfin_skip_tls_verify: bool,
+
+    #[arg(long, env, default_value = "5", help = "Timeout in seconds for connecting to Jellyfin")]
+    pub jellyfin_connect_timeout: u64,
+
+    #[arg(long, env, default_value = "60", help = "Timeout in seconds for a single API request including the response body. The recursive /Items requests of large libraries take the longest")]
+    pub jellyfin_request_timeout: u64,
+
+    #[arg(long, env, default_value = "2", help = "How often a failed GET request is retried on timeouts, connection errors, 429 and 5xx responses")]
+    pub jellyfin_retries: u32,
+
+    #[arg(long, env, default_value = "500", help = "Delay in milliseconds before the first retry, doubled for every further retry")]
+    pub jellyfin_retry_backoff: u64,
+
+    #[arg(long, env, hide_env_values = true, conflicts_with = "jellyfin_api_key_file", help = "Prefer --jellyfin-api-key-file, the key is visible in the process list")]
+    pub jellyfin_api_key: Option<Secret>,
+
+    #[arg(long, env, help = "File containing the API key, re-read on SIGHUP. Defaults to the systemd credential `jellyfin-api-key` in $CREDENTIALS_DIRECTORY")]
+    pub jellyfin_api_key_file: Option<PathBuf>,
+
+    #[arg(long, env, conflicts_with_all = ["jellyfin_api_key", "jellyfin_api_key_file"], help = "Log in with a username and password instead of an API key. The user needs to be an administrator")]
+    pub jellyfin_username: Option<String>,
+
+    #[arg(long, env, requires = "jellyfin_username", help = "File containing the password, re-read on SIGHUP. Defaults to the systemd credential `jellyfin-password` in $CREDENTIALS_DIRECTORY")]
+    pub jellyfin_password_file: Option<PathBuf>,
+
+    #[arg(long, env, value_enum, default_value_t = Preset::Full, help = "Which metrics are exported. Parsing the library content results in expensive API calls, use a smaller preset to decrease CPU / Memory usage")]
+    pub jellyfin_exporter_preset: Preset,
+
+    #[arg(long, env, value_enum, default_value_t = MetricLayout::Labels, help = "How per-item metrics are laid out. `info` only exports descriptive labels on `jellyfin_item_info` and keys all values by item_id")]
+    pub jellyfin_exporter_metric_layout: MetricLayout,
+
+    #[arg(long, env, help = "Maximum number of series exported per metric. Excess series are dropped and counted in jellyfin_exporter_series_dropped_total")]
+    pub jellyfin_exporter_max_series_per_metric: Option<usize>,
+
+    #[arg(long, env, default_value = "900", help = "Rolling window in seconds over which the peak number of concurrent streams is computed")]
+    pub jellyfin_exporter_peak_window: u64,
+
+    #[arg(long, env, default_value = "10", help = "Seconds between polls of /Sessions for the peak concurrency when not using the WebSocket, independent of scrapes. 0 only samples on scrapes, which misses short peaks")]
+    pub jellyfin_exporter_peak_sample_interval: u64,
+
+    #[arg(long, env, default_value = "0.5", value_parser = parse_seconds, help = "Seconds subtracted from the X-Prometheus-Scrape-Timeout-Seconds header of a scrape, collectors still running at the resulting deadline are cancelled. Leaves time to send the response")]
+    pub jellyfin_exporter_scrape_timeout_offset: f64,
+
+    #[arg(long, env, default_value = "0", help = "Minimum seconds between two collections. Scrapes within this interval, e.g. of several Prometheus replicas, get the metrics of the last collection")]
+    pub jellyfin_exporter_min_interval: u64,
+
+    #[arg(long, env, default_value = "5", help = "Consecutive failed or slow requests to Jellyfin after which expensive collectors are paused and their last values are exported. 0 disables the circuit breaker")]
+    pub jellyfin_exporter_circuit_breaker_failures: u32,
 
-    #[arg(long, env, default_value = "false", help = "Disable recursive item search. Helps to decrease CPU / Memory usage as this results in expensive API calls")]
-    pub jellyfin_exporter_disable_recursive_item_search: bool,
+    #[arg(long, env, default_value = "30", help = "Requests taking longer than this many seconds count as failed for the circuit breaker")]
+    pub jellyfin_exporter_circuit_breaker_latency: u64,
+
+    #[arg(long, env, default_value = "300", help = "Seconds the circuit breaker stays open before the expensive collectors are tried again")]
+    pub jellyfin_exporter_circuit_breaker_cooldown: u64,
+
+    #[arg(long, env, help = "Receive sessions over the Jellyfin WebSocket instead of polling them on every scrape")]
+    pub jellyfin_exporter_websocket: bool,
+
+    #[arg(long, env, value_delimiter = ',', default_value = "10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,127.0.0.0/8,169.254.0.0/16,::1/128,fc00::/7,fe80::/10", help = "Comma-separated CIDRs of the local network, sessions from other addresses are remote")]
+    pub jellyfin_exporter_local_networks: Vec<IpNet>,
+
+    #[arg(long, env, value_delimiter = ',', help = "Comma-separated CIDRs of VPN clients. Takes precedence over the local networks, as VPN ranges are usually private")]
+    pub jellyfin_exporter_vpn_networks: Vec<IpNet>,
+
+    #[arg(long, env, help = "Do not export the remote address of sessions, only the network class it belongs to")]
+    pub jellyfin_exporter_drop_remote_address: bool,
+
+    #[arg(long, env, help = "MaxMind City or Country database (e.g. GeoLite2-City.mmdb) to resolve the location of session addresses")]
+    pub jellyfin_exporter_geoip_database: Option<PathBuf>,
+
+    #[arg(long, env, help = "MaxMind ASN database (e.g. GeoLite2-ASN.mmdb) to resolve the autonomous system of session addresses")]
+    pub jellyfin_exporter_geoip_asn_database: Option<PathBuf>,
+
+    #[arg(long, env, value_delimiter = ',', help = "Comma-separated rules applied to label values before they are exported: <label>=drop, <label>=truncate:<n> or <label>=hash, e.g. user_name=hash,remote_end_point=drop,path=truncate:0")]
+    pub jellyfin_exporter_privacy: Vec<PrivacyRule>,
+
+    #[arg(long, env, hide_env_values = true, help = "Secret salt for hashed labels, keep it stable to keep the hashes stable")]
+    pub jellyfin_exporter_privacy_salt: Option<Secret>,
+
+    #[arg(long, env, help = "Accept events from the Jellyfin Webhook plugin on POST /webhook")]
+    pub jellyfin_exporter_webhook: bool,
+
+    #[arg(long, env, hide_env_values = true, help = "Only accept webhook requests carrying this secret in the X-Webhook-Secret header")]
+    pub jellyfin_exporter_webhook_secret: Option<Secret>,
+
+    #[command(flatten)]
+    pub collectors: CollectorFlags,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Validate the configuration and print the effective configuration with secrets redacted
+    CheckConfig,
+}
+
+/// Enables or disables a single collector regardless of the preset. If both flags are given, the last one wins.
+#[derive(Args, Debug, Clone)]
+pub struct CollectorFlags {
+    #[arg(long = "collector.config", overrides_with = "no_collector_config", help = "Enable the config collector")]
+    collector_config: bool,
+    #[arg(long = "no-collector.config", help = "Disable the config collector")]
+    no_collector_config: bool,
+
+    #[arg(long = "collector.users", overrides_with = "no_collector_users", help = "Enable the users collector")]
+    collector_users: bool,
+    #[arg(long = "no-collector.users", help = "Disable the users collector")]
+    no_collector_users: bool,
+
+    #[arg(long = "collector.sessions", overrides_with = "no_collector_sessions", help = "Enable the sessions collector")]
+    collector_sessions: bool,
+    #[arg(long = "no-collector.sessions", help = "Disable the sessions collector")]
+    no_collector_sessions: bool,
+
+    #[arg(long = "collector.devices", overrides_with = "no_collector_devices", help = "Enable the devices collector")]
+    collector_devices: bool,
+    #[arg(long = "no-collector.devices", help = "Disable the devices collector")]
+    no_collector_devices: bool,
+
+    #[arg(long = "collector.item_counts", overrides_with = "no_collector_item_counts", help = "Enable the item counts collector")]
+    collector_item_counts: bool,
+    #[arg(long = "no-collector.item_counts", help = "Disable the item counts collector")]
+    no_collector_item_counts: bool,
+
+    #[arg(long = "collector.items", overrides_with = "no_collector_items", help = "Enable the items collector")]
+    collector_items: bool,
+    #[arg(long = "no-collector.items", help = "Disable the items collector")]
+    no_collector_items: bool,
+}
+
+impl CollectorFlags {
+    const NAMES: [&'static str; 6] = ["config", "users", "sessions", "devices", "item_counts", "items"];
+
+    /// Returns `None` if the collector was not explicitly enabled or disabled
+    pub fn is_enabled(&self, collector: &str) -> Option<bool> {
+        let (enabled, disabled) = match collector {
+            "config" => (self.collector_config, self.no_collector_config),
+            "users" => (self.collector_users, self.no_collector_users),
+            "sessions" => (self.collector_sessions, self.no_collector_sessions),
+            "devices" => (self.collector_devices, self.no_collector_devices),
+            "item_counts" => (self.collector_item_counts, self.no_collector_item_counts),
+            "items" => (self.collector_items, self.no_collector_items),
+            _ => (false, false),
+        };
+
+        match (enabled, disabled) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Preset {
+    /// Server, users, sessions and item counts. Does not query the library content at all
+    Minimal,
+    /// Additionally devices and library aggregates (libraries, series rollups). Crawls the library, but does not export per-item series
+    Standard,
+    /// Additionally every item (media items, seasons, episodes) with its user data
+    Full,
+}
+
+impl Preset {
+    pub fn exports_item_series(self) -> bool {
+        self >= Preset::Full
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricLayout {
+    /// Every item metric carries all of its descriptive labels
+    Labels,
+    /// Descriptive labels only live on `jellyfin_item_info`, numeric values are keyed by item_id
+    Info,
 }
 
 pub fn parse_url(url: &str) -> Result<Url, String> {
@@ -37,26 +226,48 @@ pub fn parse_url(url: &str) -> Result<Url, String> {
         Err(it) => Err(it.to_string())?,
     };
 
-    // We can't use the clap config object to evaluate the insecure flag, so do it manually
-    let is_insecure = env::args().any(|arg| arg == "--insecure") || env::var("INSECURE").is_ok();
     let scheme = url.scheme();
 
-    if !is_insecure && scheme == "http" {
-        let ip_addrs = match url.socket_addrs(|| None) {
-            Ok(it) => it,
-            Err(it) => Err(it.to_string())?,
-        };
+    if scheme != "http" && scheme != "https" {
+        return Err(format!("Invalid scheme: \"{scheme}\""));
+    }
+
+    Ok(url)
+}
+
+impl Cli {
+    /// Checks the combination of the address and the TLS options. Runs after the command line, env vars and config file are merged,
+    /// as the value parser of the address can't see `--jellyfin-exporter-insecure`.
+    pub fn check_transport_security(&self) -> Result<(), String> {
+        let url = &self.jellyfin_address;
+
+        if url.scheme() == "https" {
+            return Ok(());
+        }
+
+        if self.jellyfin_ca_file.is_some() || self.jellyfin_client_cert_file.is_some() || self.jellyfin_skip_tls_verify {
+            return Err(format!("The TLS options require an https address, got {url}"));
+        }
 
+        if self.jellyfin_exporter_insecure {
+            return Ok(());
+        }
+
+        let ip_addrs = url.socket_addrs(|| None).map_err(|e| e.to_string())?;
         if ip_addrs.into_iter().any(|it| !it.ip().is_loopback()) {
-            return Err("Insecure connection detected: http with a non-loopback (localhost) address! Aborting to not send the API Key via plain text!\n\nTo override this behaviour specify the --insecure flag".into());
+            return Err("Insecure connection detected: http with a non-loopback (localhost) address! Aborting to not send the API Key via plain text!\n\nTo override this behaviour specify the --jellyfin-exporter-insecure flag".into());
         }
-    };
 
-    if scheme != "http" && scheme != "https" {
-        return Err(format!("Invalid scheme: \"{scheme}\""));
+        Ok(())
     }
+}
 
-    Ok(url)
+pub fn parse_seconds(seconds: &str) -> Result<f64, String> {
+    match seconds.parse::<f64>() {
+        Ok(it) if it.is_finite() && it >= 0.0 => Ok(it),
+        Ok(_) => Err(format!("Expected a non-negative number of seconds, got {seconds}")),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
 pub fn parse_loglevel(level: &str) -> Result<String, String> {
@@ -64,7 +275,7 @@ pub fn parse_loglevel(level: &str) -> Result<String, String> {
     let level = level.to_lowercase();
     if level == "off" || level == "error" || level == "warn" || level == "info" || level == "debug" || level == "trace" {
         unsafe { env::set_var("RUST_LOG", &level) };
-        return Ok(level.into());
+        return Ok(level);
     }
 
     Err(format!(r#"Expected loglevel to be in {{"off", "error", "warn", "info", "debug", "trace"}}, got "{level}""#))
@@ -75,22 +286,86 @@ impl Display for Cli {
         writeln!(
             f,
             r#"Cli {{
+    config                     = {:?}
+
     jellyfin_exporter_address  = {}
     jellyfin_exporter_port     = {}
     jellyfin_exporter_loglevel = {}
     jellyfin_exporter_insecure = {}
 
     jellyfin_address           = {}
-    jellyfin_api_key           = <REDACTED>
+    jellyfin_ca_file           = {:?}
+    jellyfin_client_cert_file  = {:?}
+    jellyfin_client_key_file   = {:?}
+    jellyfin_skip_tls_verify   = {}
+    jellyfin_connect_timeout   = {}s
+    jellyfin_request_timeout   = {}s
+    jellyfin_retries           = {}
+    jellyfin_retry_backoff     = {}ms
+    jellyfin_api_key           = {:?}
+    jellyfin_api_key_file      = {:?}
+    jellyfin_username          = {:?}
+    jellyfin_password_file     = {:?}
 
-    disable_recursive_item_search = {}
+    preset                = {:?}
+    collectors            = {}
+    metric_layout         = {:?}
+    max_series_per_metric = {:?}
+    peak_window           = {}s
+    peak_sample_interval  = {}s
+    scrape_timeout_offset = {}s
+    min_interval          = {}s
+    circuit_breaker       = failures={} latency={}s cooldown={}s
+    websocket             = {}
+    local_networks        = {:?}
+    vpn_networks          = {:?}
+    drop_remote_address   = {}
+    geoip_database        = {:?}
+    geoip_asn_database    = {:?}
+    privacy               = {}
+    privacy_salt          = {:?}
+    webhook               = {}
+    webhook_secret        = {:?}
 }}"#,
+            self.config,
             self.jellyfin_exporter_address,
             self.jellyfin_exporter_port,
             self.jellyfin_exporter_loglevel,
             self.jellyfin_exporter_insecure,
-            self.jellyfin_address.to_string(),
-            self.jellyfin_exporter_disable_recursive_item_search,
+            self.jellyfin_address,
+            self.jellyfin_ca_file,
+            self.jellyfin_client_cert_file,
+            self.jellyfin_client_key_file,
+            self.jellyfin_skip_tls_verify,
+            self.jellyfin_connect_timeout,
+            self.jellyfin_request_timeout,
+            self.jellyfin_retries,
+            self.jellyfin_retry_backoff,
+            self.jellyfin_api_key,
+            self.jellyfin_api_key_file,
+            self.jellyfin_username,
+            self.jellyfin_password_file,
+            self.jellyfin_exporter_preset,
+            CollectorFlags::NAMES.iter().filter_map(|it| Some(format!("{}={}", it, self.collectors.is_enabled(it)?))).collect::<Vec<_>>().join(","),
+            self.jellyfin_exporter_metric_layout,
+            self.jellyfin_exporter_max_series_per_metric,
+            self.jellyfin_exporter_peak_window,
+            self.jellyfin_exporter_peak_sample_interval,
+            self.jellyfin_exporter_scrape_timeout_offset,
+            self.jellyfin_exporter_min_interval,
+            self.jellyfin_exporter_circuit_breaker_failures,
+            self.jellyfin_exporter_circuit_breaker_latency,
+            self.jellyfin_exporter_circuit_breaker_cooldown,
+            self.jellyfin_exporter_websocket,
+            self.jellyfin_exporter_local_networks,
+            self.jellyfin_exporter_vpn_networks,
+            self.jellyfin_exporter_drop_remote_address,
+            self.jellyfin_exporter_geoip_database,
+            self.jellyfin_exporter_geoip_asn_database,
+            self.jellyfin_exporter_privacy.iter().map(|it| it.to_string()).collect::<Vec<_>>().join(","),
+            self.jellyfin_exporter_privacy_salt,
+            self.jellyfin_exporter_webhook,
+            self.jellyfin_exporter_webhook_secret,
         )
     }
 }
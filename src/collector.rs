@@ -0,0 +1,339 @@
+use crate::api::{get_devices, get_item_counts, get_items, get_jellyfin_config, get_sessions, get_users, validate_items};
+use crate::circuit_breaker;
+use crate::cli::{Cli, Preset};
+use crate::metrics::{Item, Metrics, Session, User, reset_item_metrics, set_config_metrics, set_device_metrics, set_item_count_metrics, set_item_metrics, set_series_metrics, set_session_metrics, set_user_metrics};
+use crate::geoip::{GeoIp, set_geoip_metrics};
+use crate::network::{NetworkClassifier, set_network_metrics};
+use crate::tracker::SessionTracker;
+use crate::websocket::{SocketState, run_socket};
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use reqwest::Client;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A self-contained unit of the exporter: It fetches its data from the Jellyfin API and sets the corresponding metrics.
+///
+/// Collectors are created once at startup and run concurrently on every scrape, so they may keep state between collections.
+/// The metrics are shared between all collectors, which is fine as every metric is internally synchronized.
+pub trait Collector: Send + Sync {
+    /// The name used in the `--collector.<name>` / `--no-collector.<name>` flags and the `collector` label
+    fn name(&self) -> &'static str;
+
+    /// The smallest preset this collector is part of
+    fn preset(&self) -> Preset;
+
+    fn collect<'a>(&'a self, cli: &'a Cli, client: &'a Client, metrics: &'a Metrics) -> BoxFuture<'a, Result<(), reqwest::Error>>;
+
+    /// Expensive collectors are skipped while the circuit breaker is open, their metrics keep the values of the last collection
+    fn expensive(&self) -> bool {
+        false
+    }
+
+    /// Called before every collection, also for collectors skipped by the circuit breaker, e.g. to drop data shared within the last scrape
+    fn prepare(&self) {}
+
+    /// Called once for every enabled collector before the first collection, e.g. to spawn background tasks
+    fn start(&self, _cli: &Cli, _client: &Client, _metrics: &Metrics) {}
+}
+
+/// `geoip` is shared by the sessions collector, which resolves the addresses, and the devices collector, which prunes deleted devices
+pub fn all_collectors(cli: &Cli, geoip: Option<Arc<GeoIp>>) -> Vec<Box<dyn Collector>> {
+    let users = Arc::new(SharedUsers::default());
+
+    vec![
+        Box::new(ConfigCollector),
+        Box::new(UsersCollector { users: users.clone() }),
+        Box::new(SessionsCollector::new(cli, geoip.clone())),
+        Box::new(DevicesCollector { geoip }),
+        Box::new(ItemCountsCollector),
+        Box::new(ItemsCollector { users }),
+    ]
+}
+
+/// Explicit `--collector.<name>` / `--no-collector.<name>` flags win over the preset
+pub fn enabled_collectors(cli: &Cli, geoip: Option<Arc<GeoIp>>) -> Vec<Box<dyn Collector>> {
+    all_collectors(cli, geoip).into_iter().filter(|it| cli.collectors.is_enabled(it.name()).unwrap_or(cli.jellyfin_exporter_preset >= it.preset())).collect()
+}
+
+pub async fn run_collectors(collectors: &[Box<dyn Collector>], cli: &Cli, client: &Client, metrics: &Metrics, deadline: Option<tokio::time::Instant>) {
+    let allows_expensive = circuit_breaker::allows_expensive(cli, metrics);
+    for collector in collectors {
+        collector.prepare();
+    }
+
+    futures::future::join_all(collectors.iter().map(|collector| async move {
+        let cached = collector.expensive() && !allows_expensive;
+        metrics.jellyfin_exporter_collector_cached.with_label_values(&[collector.name()]).set(cached as i64);
+        if cached {
+            debug!("Skipping collector {}, the circuit breaker is open", collector.name());
+            return;
+        }
+
+        let s = Instant::now();
+        // Dropping the future on the deadline cancels its requests. Metrics are only set after all requests of a collector finished,
+        // so a cancelled collector keeps the values of its last collection.
+        let result = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, collector.collect(cli, client, metrics)).await,
+            None => Ok(collector.collect(cli, client, metrics).await),
+        };
+        metrics.jellyfin_exporter_collector_duration_seconds.with_label_values(&[collector.name()]).set(s.elapsed().as_secs_f64());
+
+        match result {
+            Ok(Ok(())) => metrics.jellyfin_exporter_collector_success.with_label_values(&[collector.name()]).set(1),
+            Ok(Err(e)) => {
+                warn!("Collector {} failed: {:?}", collector.name(), e);
+                metrics.jellyfin_exporter_collector_success.with_label_values(&[collector.name()]).set(0)
+            }
+            Err(_) => {
+                warn!("Collector {} was cancelled after {:?}, it did not finish within the scrape timeout", collector.name(), s.elapsed());
+                metrics.jellyfin_exporter_collector_success.with_label_values(&[collector.name()]).set(0);
+                circuit_breaker::record_cancelled(cli, metrics, collector.name(), s.elapsed());
+            }
+        }
+    }))
+    .await;
+
+    circuit_breaker::scrape_finished(metrics);
+}
+
+
+pub struct ConfigCollector;
+
+impl Collector for ConfigCollector {
+    fn name(&self) -> &'static str {
+        "config"
+    }
+
+    fn preset(&self) -> Preset {
+        Preset::Minimal
+    }
+
+    fn collect<'a>(&'a self, cli: &'a Cli, client: &'a Client, metrics: &'a Metrics) -> BoxFuture<'a, Result<(), reqwest::Error>> {
+        Box::pin(async move {
+            set_config_metrics(&get_jellyfin_config(cli, client, metrics).await?, metrics);
+            Ok(())
+        })
+    }
+}
+
+/// `/Users` is needed by the users and the items collector, which run concurrently. The first one fetches it, the other one waits for the result.
+#[derive(Default)]
+pub struct SharedUsers {
+    users: tokio::sync::Mutex<Option<Vec<User>>>,
+}
+
+impl SharedUsers {
+    async fn get(&self, cli: &Cli, client: &Client, metrics: &Metrics) -> Result<Vec<User>, reqwest::Error> {
+        let mut users = self.users.lock().await;
+        if let Some(it) = &*users {
+            return Ok(it.clone());
+        }
+
+        let fetched = get_users(cli, client, metrics).await?;
+        *users = Some(fetched.clone());
+
+        Ok(fetched)
+    }
+
+    fn clear(&self) {
+        // Only the collectors hold the lock, and they are not running between scrapes
+        if let Ok(mut it) = self.users.try_lock() {
+            *it = None;
+        }
+    }
+}
+
+pub struct UsersCollector {
+    users: Arc<SharedUsers>,
+}
+
+impl Collector for UsersCollector {
+    fn name(&self) -> &'static str {
+        "users"
+    }
+
+    fn preset(&self) -> Preset {
+        Preset::Minimal
+    }
+
+    fn collect<'a>(&'a self, cli: &'a Cli, client: &'a Client, metrics: &'a Metrics) -> BoxFuture<'a, Result<(), reqwest::Error>> {
+        Box::pin(async move {
+            set_user_metrics(&self.users.get(cli, client, metrics).await?, metrics);
+            Ok(())
+        })
+    }
+
+    fn prepare(&self) {
+        self.users.clear();
+    }
+}
+
+/// Besides the current sessions, this keeps track of the sessions between collections to derive counters like the watch time
+///
+/// With `--jellyfin-exporter-websocket` the sessions are pushed by Jellyfin instead, polling is only used while the socket is disconnected.
+pub struct SessionsCollector {
+    tracker: Arc<Mutex<SessionTracker>>,
+    socket: Arc<SocketState>,
+    network: NetworkClassifier,
+}
+
+impl SessionsCollector {
+    pub fn new(cli: &Cli, geoip: Option<Arc<GeoIp>>) -> Self {
+        SessionsCollector { tracker: Arc::new(Mutex::new(SessionTracker::new(Duration::from_secs(cli.jellyfin_exporter_peak_window)))), socket: Default::default(), network: NetworkClassifier::new(cli, geoip) }
+    }
+}
+
+impl SessionsCollector {
+    fn set_metrics(&self, sessions: &Vec<Session>, metrics: &Metrics) {
+        set_session_metrics(sessions, metrics);
+        set_network_metrics(sessions, metrics);
+
+        if let Some(geoip) = self.network.geoip() {
+            set_geoip_metrics(sessions, geoip, metrics);
+        }
+    }
+}
+
+impl Collector for SessionsCollector {
+    fn name(&self) -> &'static str {
+        "sessions"
+    }
+
+    fn preset(&self) -> Preset {
+        Preset::Minimal
+    }
+
+    fn collect<'a>(&'a self, cli: &'a Cli, client: &'a Client, metrics: &'a Metrics) -> BoxFuture<'a, Result<(), reqwest::Error>> {
+        Box::pin(async move {
+            // The tracker is already updated on every message of the socket
+            if let Some(sessions) = &*self.socket.sessions.lock().unwrap() {
+                self.set_metrics(sessions, metrics);
+                return Ok(());
+            }
+
+            let mut sessions = get_sessions(cli, client, metrics).await?;
+            self.network.classify_sessions(&mut sessions);
+            self.set_metrics(&sessions, metrics);
+            self.tracker.lock().unwrap().update(&sessions, Instant::now(), metrics);
+
+            Ok(())
+        })
+    }
+
+    fn start(&self, cli: &Cli, client: &Client, metrics: &Metrics) {
+        if cli.jellyfin_exporter_websocket {
+            tokio::spawn(run_socket(cli.clone(), self.network.clone(), self.socket.clone(), self.tracker.clone(), metrics.clone()));
+        } else if cli.jellyfin_exporter_peak_sample_interval > 0 {
+            tokio::spawn(sample_sessions(cli.clone(), client.clone(), self.network.clone(), self.tracker.clone(), metrics.clone()));
+        }
+    }
+}
+
+/// Polls the sessions on a fixed interval, so the peak concurrency does not depend on how often (or whether) Prometheus scrapes
+async fn sample_sessions(cli: Cli, client: Client, network: NetworkClassifier, tracker: Arc<Mutex<SessionTracker>>, metrics: Metrics) {
+    let mut interval = tokio::time::interval(Duration::from_secs(cli.jellyfin_exporter_peak_sample_interval));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        match get_sessions(&cli, &client, &metrics).await {
+            Ok(mut sessions) => {
+                network.classify_sessions(&mut sessions);
+                tracker.lock().unwrap().update(&sessions, Instant::now(), &metrics);
+            }
+            Err(e) => debug!("Could not sample the sessions: {:?}", e),
+        }
+    }
+}
+
+pub struct DevicesCollector {
+    geoip: Option<Arc<GeoIp>>,
+}
+
+impl Collector for DevicesCollector {
+    fn name(&self) -> &'static str {
+        "devices"
+    }
+
+    fn preset(&self) -> Preset {
+        Preset::Standard
+    }
+
+    fn collect<'a>(&'a self, cli: &'a Cli, client: &'a Client, metrics: &'a Metrics) -> BoxFuture<'a, Result<(), reqwest::Error>> {
+        Box::pin(async move {
+            let devices = get_devices(cli, client, metrics).await?;
+            set_device_metrics(&devices, metrics);
+
+            if let Some(geoip) = &self.geoip {
+                geoip.retain_devices(&devices.iter().map(|it| it.id.as_str()).collect());
+            }
+
+            Ok(())
+        })
+    }
+}
+
+pub struct ItemCountsCollector;
+
+impl Collector for ItemCountsCollector {
+    fn name(&self) -> &'static str {
+        "item_counts"
+    }
+
+    fn preset(&self) -> Preset {
+        Preset::Minimal
+    }
+
+    fn collect<'a>(&'a self, cli: &'a Cli, client: &'a Client, metrics: &'a Metrics) -> BoxFuture<'a, Result<(), reqwest::Error>> {
+        Box::pin(async move {
+            set_item_count_metrics(&get_item_counts(cli, client, metrics).await?, metrics);
+            Ok(())
+        })
+    }
+}
+
+/// Crawls the library of every user. With the `standard` preset only library aggregates are exported, `full` additionally exports every item.
+pub struct ItemsCollector {
+    users: Arc<SharedUsers>,
+}
+
+impl Collector for ItemsCollector {
+    fn name(&self) -> &'static str {
+        "items"
+    }
+
+    fn preset(&self) -> Preset {
+        Preset::Standard
+    }
+
+    // A recursive crawl of every library per user
+    fn expensive(&self) -> bool {
+        true
+    }
+
+    fn collect<'a>(&'a self, cli: &'a Cli, client: &'a Client, metrics: &'a Metrics) -> BoxFuture<'a, Result<(), reqwest::Error>> {
+        Box::pin(async move {
+            let items = get_items(cli, client, metrics, self.users.get(cli, client, metrics).await?).await;
+            reset_item_metrics(metrics);
+
+            for (user, mut items) in items {
+                validate_items(&items);
+                set_series_metrics(&items, metrics, &user);
+
+                if !cli.jellyfin_exporter_preset.exports_item_series() {
+                    items.retain(|it| matches!(it, Item::CollectionFolder(_)));
+                }
+
+                set_item_metrics(&items, metrics, &user, cli.jellyfin_exporter_metric_layout);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn prepare(&self) {
+        self.users.clear();
+    }
+}
@@ -0,0 +1,169 @@
+use crate::cli::Cli;
+use clap::error::ErrorKind;
+use clap::{Arg, ArgAction, Command, CommandFactory, FromArgMatches};
+use serde_json::Value;
+use std::env;
+use std::path::{Path, PathBuf};
+
+const CONFIG_ENV: &str = "JELLYFIN_EXPORTER_CONFIG";
+
+/// Parses the command line, using the values of the config file as defaults. So flags and env vars always win over the file.
+///
+/// The keys of the file are the option names, e.g. `jellyfin_address` or `jellyfin-exporter-preset`. Tables are joined with a dot,
+/// which allows `[collector]` / `users = false` for the collector flags.
+pub fn load_cli() -> Cli {
+    let mut command = Cli::command();
+
+    if let Some(path) = config_path() {
+        command = with_config(command, &path).unwrap_or_else(|e| e.exit());
+    }
+
+    let cli = Cli::from_arg_matches(&command.clone().get_matches()).unwrap_or_else(|e| e.exit());
+    cli.check_transport_security().unwrap_or_else(|e| command.error(ErrorKind::ValueValidation, e).exit());
+
+    cli
+}
+
+fn with_config(mut command: Command, path: &Path) -> Result<Command, clap::Error> {
+    let entries = read_config(path).map_err(|e| command.error(ErrorKind::Io, format!("Could not read the config file {}: {}", path.display(), e)))?;
+
+    for (key, value) in entries {
+        let (id, values) = config_arg(&command, &key, value).map_err(|e| command.error(ErrorKind::UnknownArgument, format!("Invalid key `{}` in the config file {}: {}", key, path.display(), e)))?;
+
+        // A collector flag on the command line has to win over both flags of the pair in the file, otherwise both would be set
+        if collector_on_command_line(&id) {
+            continue;
+        }
+
+        // The file may contain secrets like the API key, which must not show up as `[default: ...]` in `--help`.
+        // Flags don't show their default, and clap does not allow hiding it for them.
+        command = command.mut_arg(id, |arg| {
+            let takes_values = arg.get_action().takes_values();
+            arg.default_values(values).required(false).hide_default_value(takes_values)
+        });
+    }
+
+    Ok(command)
+}
+
+// The config file has to be known before the command line is parsed, as it changes the defaults of the parser
+fn config_path() -> Option<PathBuf> {
+    let mut args = env::args_os().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|it| it.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+
+    env::var_os(CONFIG_ENV).map(PathBuf::from)
+}
+
+fn collector_on_command_line(id: &str) -> bool {
+    let Some(name) = id.strip_prefix("collector_").or_else(|| id.strip_prefix("no_collector_")) else { return false };
+    env::args().any(|it| it == format!("--collector.{name}") || it == format!("--no-collector.{name}"))
+}
+
+/// Reads a TOML or YAML (by the `.yaml` / `.yml` extension) file into flat key-value pairs
+fn read_config(path: &Path) -> Result<Vec<(String, Value)>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+    let value = match path.extension().and_then(|it| it.to_str()) {
+        Some("yaml" | "yml") => serde_yaml_ng::from_str::<Value>(&content).map_err(|e| e.to_string())?,
+        _ => toml::from_str::<Value>(&content).map_err(|e| e.to_string())?,
+    };
+
+    let mut entries = Vec::new();
+    flatten("", value, &mut entries);
+
+    Ok(entries)
+}
+
+fn flatten(prefix: &str, value: Value, entries: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(it) => {
+            for (key, value) in it {
+                let key = if prefix.is_empty() { key } else { format!("{prefix}.{key}") };
+                flatten(&key, value, entries);
+            }
+        }
+        Value::Null => {}
+        it => entries.push((prefix.to_string(), it)),
+    }
+}
+
+/// Finds the argument a key of the config file belongs to and converts the value to what clap would get on the command line
+fn config_arg(command: &clap::Command, key: &str, value: Value) -> Result<(String, Vec<String>), String> {
+    let normalize = |it: &str| it.replace('-', "_");
+    let find = |key: &str| command.get_arguments().find(|it| normalize(it.get_id().as_str()) == normalize(key) || it.get_long().is_some_and(|it| normalize(it) == normalize(key)));
+
+    let arg = find(key).filter(|it| it.get_id() != "config").ok_or("no such option")?;
+
+    // `collector.users = false` is the same as `--no-collector.users`
+    if let (Some(long), Value::Bool(false)) = (arg.get_long(), &value)
+        && long.starts_with("collector.")
+    {
+        let negated = find(&format!("no-{long}")).ok_or("no such option")?;
+        return Ok((negated.get_id().to_string(), vec!["true".to_string()]));
+    }
+
+    let values = match value {
+        Value::Array(it) if accepts_multiple(arg) => it.into_iter().map(value_to_string).collect::<Result<_, _>>()?,
+        Value::Array(_) => return Err("expected a single value".to_string()),
+        it => vec![value_to_string(it)?],
+    };
+
+    Ok((arg.get_id().to_string(), values))
+}
+
+fn accepts_multiple(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append) || arg.get_value_delimiter().is_some()
+}
+
+fn value_to_string(value: Value) -> Result<String, String> {
+    match value {
+        Value::String(it) => Ok(it),
+        Value::Number(it) => Ok(it.to_string()),
+        Value::Bool(it) => Ok(it.to_string()),
+        _ => Err("expected a string, number or boolean".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_flags_from_the_config_file() {
+        let path = env::temp_dir().join(format!("jellyfin-exporter-{}.toml", std::process::id()));
+        std::fs::write(&path, "jellyfin_address = \"http://localhost:8096\"\njellyfin_exporter_websocket = true\n\n[collector]\nusers = false\n").unwrap();
+
+        let command = with_config(Cli::command(), &path);
+        std::fs::remove_file(&path).unwrap();
+
+        let cli = Cli::from_arg_matches(&command.unwrap().try_get_matches_from(["jellyfin-exporter"]).unwrap()).unwrap();
+        assert!(cli.jellyfin_exporter_websocket);
+        assert_eq!(cli.collectors.is_enabled("users"), Some(false));
+        assert_eq!(cli.jellyfin_address.as_str(), "http://localhost:8096/");
+    }
+
+    #[test]
+    fn reads_yaml_files() {
+        let path = env::temp_dir().join(format!("jellyfin-exporter-{}.yaml", std::process::id()));
+        std::fs::write(&path, "jellyfin_address: http://localhost:8096\njellyfin_exporter_local_networks: [10.0.0.0/8, 192.168.0.0/16]\ncollector:\n  items: true\n").unwrap();
+
+        let entries = read_config(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut entries = entries.unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(entries, [
+            ("collector.items".to_string(), Value::Bool(true)),
+            ("jellyfin_address".to_string(), Value::String("http://localhost:8096".to_string())),
+            ("jellyfin_exporter_local_networks".to_string(), serde_json::json!(["10.0.0.0/8", "192.168.0.0/16"])),
+        ]);
+    }
+}
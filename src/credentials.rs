@@ -0,0 +1,172 @@
+use crate::api::authenticate_by_name;
+use crate::cli::Cli;
+use log::{error, info};
+use reqwest::{Client, StatusCode};
+use std::convert::Infallible;
+use std::env;
+use std::fmt::{Debug, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// The names of the credentials in `LoadCredential=jellyfin-api-key:/path/to/key` of the systemd unit
+const API_KEY_CREDENTIAL: &str = "jellyfin-api-key";
+const PASSWORD_CREDENTIAL: &str = "jellyfin-password";
+
+// Either the API key or the token of the last authentication with the username and password
+static ACCESS_TOKEN: RwLock<Secret> = RwLock::new(Secret(String::new()));
+static PASSWORD: RwLock<Secret> = RwLock::new(Secret(String::new()));
+// Concurrent requests failing with the same expired token only authenticate once
+static AUTHENTICATION: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+// Every failed login counts towards `LoginAttemptsBeforeLockout` of Jellyfin, so they must not be repeated for every request
+static LOGIN_BLOCK: Mutex<LoginBlock> = Mutex::new(LoginBlock::None);
+// After a login failed for another reason than the credentials, e.g. Jellyfin restarting
+const LOGIN_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+enum LoginBlock {
+    None,
+    Until(Instant),
+    // The password was rejected, trying it again would only lock the account
+    UntilReload,
+}
+
+/// A value that must never end up in a log line: Both `Debug` and `Display` print `<REDACTED>`, only `expose` returns the value.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_string()))
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<REDACTED>")
+    }
+}
+
+impl Display for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<REDACTED>")
+    }
+}
+
+/// Reads a secret from a file, ignoring the trailing newline most editors add
+pub fn read_secret(path: &Path) -> Result<Secret, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let secret = content.trim_end_matches(['\r', '\n']);
+
+    if secret.is_empty() {
+        return Err(format!("{} is empty", path.display()));
+    }
+
+    Ok(Secret(secret.to_string()))
+}
+
+/// With `--jellyfin-username` this loads the password, the token is requested on the first request (see `reauthenticate`).
+///
+/// Otherwise the API key, in order of precedence: `--jellyfin-api-key`, `--jellyfin-api-key-file` or the systemd credential `jellyfin-api-key`
+pub fn load_credentials(cli: &Cli) -> Result<(), String> {
+    if cli.jellyfin_username.is_some() {
+        let password = match (&cli.jellyfin_password_file, env::var_os("CREDENTIALS_DIRECTORY")) {
+            (Some(path), _) => read_secret(path)?,
+            (None, Some(directory)) => read_secret(&PathBuf::from(directory).join(PASSWORD_CREDENTIAL))?,
+            (None, None) => return Err(format!("No password given: Use --jellyfin-password-file or the systemd credential {PASSWORD_CREDENTIAL}")),
+        };
+
+        *PASSWORD.write().unwrap() = password;
+        *LOGIN_BLOCK.lock().unwrap() = LoginBlock::None;
+        return Ok(());
+    }
+
+    let key = match (&cli.jellyfin_api_key, &cli.jellyfin_api_key_file) {
+        (Some(key), _) => key.clone(),
+        (None, Some(path)) => read_secret(path)?,
+        (None, None) => match env::var_os("CREDENTIALS_DIRECTORY") {
+            Some(directory) => read_secret(&PathBuf::from(directory).join(API_KEY_CREDENTIAL))?,
+            None => return Err(format!("No API key given: Use --jellyfin-api-key, --jellyfin-api-key-file or the systemd credential {API_KEY_CREDENTIAL}")),
+        },
+    };
+
+    *ACCESS_TOKEN.write().unwrap() = key;
+    Ok(())
+}
+
+pub fn access_token() -> Secret {
+    ACCESS_TOKEN.read().unwrap().clone()
+}
+
+/// Requests a new token with the username and password, unless another request already replaced the `expired` token in the meantime.
+///
+/// Returns whether the request should be sent again: After a failed login, no login is attempted until `LOGIN_RETRY_DELAY` passed,
+/// or until the password is reloaded on SIGHUP if Jellyfin rejected it.
+pub async fn reauthenticate(cli: &Cli, client: &Client, expired: &Secret) -> Result<bool, reqwest::Error> {
+    let Some(username) = &cli.jellyfin_username else { return Ok(false) };
+    let _guard = AUTHENTICATION.lock().await;
+
+    if access_token() != *expired {
+        return Ok(true);
+    }
+
+    match *LOGIN_BLOCK.lock().unwrap() {
+        LoginBlock::Until(it) if Instant::now() < it => return Ok(false),
+        LoginBlock::UntilReload => return Ok(false),
+        _ => {}
+    }
+
+    let password = PASSWORD.read().unwrap().clone();
+    let token = match authenticate_by_name(cli, client, username, &password).await {
+        Ok(it) => it,
+        Err(e) if e.status().is_some_and(|it| it == StatusCode::UNAUTHORIZED || it == StatusCode::FORBIDDEN) => {
+            error!("Jellyfin rejected the password of {}, not trying again until it is reloaded with SIGHUP", username);
+            *LOGIN_BLOCK.lock().unwrap() = LoginBlock::UntilReload;
+            return Err(e);
+        }
+        Err(e) => {
+            *LOGIN_BLOCK.lock().unwrap() = LoginBlock::Until(Instant::now() + LOGIN_RETRY_DELAY);
+            return Err(e);
+        }
+    };
+    *ACCESS_TOKEN.write().unwrap() = token;
+    *LOGIN_BLOCK.lock().unwrap() = LoginBlock::None;
+
+    info!("Authenticated as {}", username);
+    Ok(true)
+}
+
+/// Re-reads the API key or password on SIGHUP, so a rotated file is picked up without a restart
+#[cfg(unix)]
+pub async fn reload_on_hangup(cli: Cli) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(it) => it,
+        Err(e) => {
+            error!("Could not listen for SIGHUP: {:?}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        match load_credentials(&cli) {
+            Ok(()) => info!("Reloaded the credentials"),
+            Err(e) => error!("Could not reload the credentials, keeping the previous ones: {}", e),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::cli::Cli;
+use crate::metrics::{Metrics, Session};
+use crate::privacy::apply;
+use log::{info, warn};
+use maxminddb::{MaxMindDBError, Reader, geoip2};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Remote addresses of sessions change rarely, but the cache must not grow without bounds
+const MAX_CACHED_LOOKUPS: usize = 10_000;
+// Devices are pruned against `/Devices` by the devices collector, this bounds the map when that collector is disabled
+const MAX_DEVICES: usize = 10_000;
+
+/// Where an address is located, empty if the database does not know it
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub country: String,
+    pub city: String,
+    pub asn: String,
+}
+
+/// Resolves addresses with local MaxMind databases (GeoLite2 / GeoIP2), no network access is needed
+pub struct GeoIp {
+    city: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+
+    cache: Mutex<HashMap<IpAddr, Option<Location>>>,
+    // Jellyfin does not report the address of a device, so the address of its last session is used
+    devices: Mutex<HashMap<String, (Location, Instant)>>,
+}
+
+impl GeoIp {
+    /// `None` if no database is configured
+    pub fn open(cli: &Cli) -> Result<Option<GeoIp>, MaxMindDBError> {
+        if cli.jellyfin_exporter_geoip_database.is_none() && cli.jellyfin_exporter_geoip_asn_database.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(GeoIp {
+            city: cli.jellyfin_exporter_geoip_database.as_deref().map(open_database).transpose()?,
+            asn: cli.jellyfin_exporter_geoip_asn_database.as_deref().map(open_database).transpose()?,
+            cache: Default::default(),
+            devices: Default::default(),
+        }))
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(it) = cache.get(&ip) {
+            return it.clone();
+        }
+
+        let location = self.resolve(ip);
+        if cache.len() >= MAX_CACHED_LOOKUPS {
+            cache.clear();
+        }
+        cache.insert(ip, location.clone());
+
+        location
+    }
+
+    fn resolve(&self, ip: IpAddr) -> Option<Location> {
+        let city = self.city.as_ref().and_then(|it| lookup::<geoip2::City>(it, ip));
+        let asn = self.asn.as_ref().and_then(|it| lookup::<geoip2::Asn>(it, ip));
+
+        if city.is_none() && asn.is_none() {
+            return None;
+        }
+
+        Some(Location {
+            country: city.as_ref().and_then(|it| it.country.as_ref()?.iso_code).unwrap_or_default().to_string(),
+            city: city.as_ref().and_then(|it| it.city.as_ref()?.names.as_ref()?.get("en").copied()).unwrap_or_default().to_string(),
+            asn: asn.and_then(|it| it.autonomous_system_number).map(|it| it.to_string()).unwrap_or_default(),
+        })
+    }
+
+    pub fn remember_device(&self, device_id: &str, location: &Location) {
+        let mut devices = self.devices.lock().unwrap();
+
+        if devices.len() >= MAX_DEVICES
+            && !devices.contains_key(device_id)
+            && let Some(oldest) = devices.iter().min_by_key(|(_, (_, seen))| *seen).map(|(id, _)| id.clone())
+        {
+            devices.remove(&oldest);
+        }
+        devices.insert(device_id.to_string(), (location.clone(), Instant::now()));
+    }
+
+    /// Forgets the locations of devices that were deleted in Jellyfin
+    pub fn retain_devices(&self, device_ids: &HashSet<&str>) {
+        self.devices.lock().unwrap().retain(|id, _| device_ids.contains(id.as_str()));
+    }
+}
+
+fn open_database(path: &Path) -> Result<Reader<Vec<u8>>, MaxMindDBError> {
+    let reader = Reader::open_readfile(path)?;
+    info!("Loaded GeoIP database {} ({})", path.display(), reader.metadata.database_type);
+
+    Ok(reader)
+}
+
+fn lookup<'a, T: serde::Deserialize<'a>>(reader: &'a Reader<Vec<u8>>, ip: IpAddr) -> Option<T> {
+    match reader.lookup::<T>(ip) {
+        Ok(it) => Some(it),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+        Err(e) => {
+            warn!("GeoIP lookup of {} failed: {:?}", ip, e);
+            None
+        }
+    }
+}
+
+pub fn set_geoip_metrics(sessions: &[Session], geoip: &GeoIp, metrics: &Metrics) {
+    metrics.jellyfin_sessions_country.reset();
+    metrics.jellyfin_session_location.reset();
+    metrics.jellyfin_device_location.reset();
+
+    for session in sessions {
+        let Some(location) = &session.location else { continue };
+
+        metrics.jellyfin_sessions_country.with_label_values(&[&location.country]).inc();
+        metrics.jellyfin_session_location.with_label_values(&[&session.id, &location.country, &location.city, &location.asn]).set(1);
+    }
+
+    for (device_id, (location, _)) in geoip.devices.lock().unwrap().iter() {
+        metrics.jellyfin_device_location.with_label_values(&[&apply("device_id", device_id), &location.country, &location.city, &location.asn]).set(1);
+    }
+}
@@ -1,21 +1,114 @@
-use crate::api::{get_devices, get_item_counts, get_items, get_jellyfin_config, get_jellyfin_up, get_sessions, get_users, validate_items};
+use crate::api::get_jellyfin_up;
+use crate::cardinality::limit_all_series;
 use crate::cli::Cli;
-use crate::metrics::{Device, ExportableMetric, Metrics, set_config_metrics, set_device_metrics, set_item_count_metrics, set_item_metrics, set_jellyfin_up, set_session_metrics, set_user_metrics};
-use log::{debug, error, warn};
-use reqwest::Client;
+use crate::collector::{Collector, run_collectors};
+use crate::credentials::access_token;
+use crate::metrics::{Metrics, set_jellyfin_up};
+use log::{error, warn};
 use reqwest::header::{HeaderMap, HeaderValue};
-use std::time::Instant;
+use reqwest::{Certificate, Client, Identity};
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::Instant;
+use url::Url;
 
 // For now, we will have blocking calls to the API, as this is the simplest way.
 // But in the future, I want to handle this with async and tokio, as parallel querying and processing of the API is essential for a responsive exporter
-pub fn client(cli: &Cli) -> Client {
-    Client::builder().default_headers(headers(cli)).build().expect("Building the HTTP Client failed")
+pub fn client(cli: &Cli) -> Result<Client, String> {
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_secs(cli.jellyfin_connect_timeout))
+        .timeout(Duration::from_secs(cli.jellyfin_request_timeout))
+        .danger_accept_invalid_certs(cli.jellyfin_skip_tls_verify);
+
+    if let Some(path) = &cli.jellyfin_ca_file {
+        for pem in ca_certificates(path)? {
+            builder = builder.add_root_certificate(Certificate::from_pem(&pem).map_err(|e| format!("Invalid certificate in {}: {}", path.display(), e))?);
+        }
+    }
+
+    if let (Some(cert), Some(key)) = (&cli.jellyfin_client_cert_file, &cli.jellyfin_client_key_file) {
+        let identity = Identity::from_pkcs8_pem(&read_file(cert)?, &read_file(key)?).map_err(|e| format!("Invalid client certificate {}: {}", cert.display(), e))?;
+        builder = builder.identity(identity);
+    }
+
+    if cli.jellyfin_skip_tls_verify {
+        warn!("TLS verification of the Jellyfin server is disabled!");
+    }
+
+    builder.build().map_err(|e| format!("Building the HTTP Client failed: {e}"))
 }
 
-pub fn headers(cli: &Cli) -> HeaderMap {
+/// The same TLS settings as `client`, for the WebSocket connection which does not go through reqwest
+pub fn tls_connector(cli: &Cli) -> Result<native_tls::TlsConnector, String> {
+    let mut builder = native_tls::TlsConnector::builder();
+    builder.danger_accept_invalid_certs(cli.jellyfin_skip_tls_verify);
+
+    if let Some(path) = &cli.jellyfin_ca_file {
+        for pem in ca_certificates(path)? {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(&pem).map_err(|e| format!("Invalid certificate in {}: {}", path.display(), e))?);
+        }
+    }
+
+    if let (Some(cert), Some(key)) = (&cli.jellyfin_client_cert_file, &cli.jellyfin_client_key_file) {
+        let identity = native_tls::Identity::from_pkcs8(&read_file(cert)?, &read_file(key)?).map_err(|e| format!("Invalid client certificate {}: {}", cert.display(), e))?;
+        builder.identity(identity);
+    }
+
+    builder.build().map_err(|e| format!("Building the TLS connector failed: {e}"))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))
+}
+
+// Splits a bundle into single certificates, as native-tls only parses the first certificate of a PEM file
+fn ca_certificates(path: &Path) -> Result<Vec<Vec<u8>>, String> {
+    const END: &str = "-----END CERTIFICATE-----";
+
+    let content = String::from_utf8(read_file(path)?).map_err(|_| format!("{} is not a PEM file", path.display()))?;
+    let certificates = content.split_inclusive(END).filter(|it| it.contains(END)).map(|it| it.trim().as_bytes().to_vec()).collect::<Vec<_>>();
+
+    if certificates.is_empty() {
+        return Err(format!("{} contains no PEM certificate", path.display()));
+    }
+
+    Ok(certificates)
+}
+
+// Jellyfin ties the tokens of a username / password login to the device, so logging in again replaces the previous token
+pub const DEVICE_ID: &str = "jellyfin-exporter";
+
+/// The URL of an API endpoint below `--jellyfin-address`, e.g. `endpoint(cli, "/Items", &[("UserId", id)])`.
+///
+/// `Url::join` with an absolute path drops the base path, which breaks a Jellyfin behind a reverse proxy at `https://example.com/jellyfin`.
+/// Here the path is appended to the base path (with or without a trailing slash) and the segments and query parameters are encoded.
+pub fn endpoint(cli: &Cli, path: &str, query: &[(&str, &str)]) -> Url {
+    let mut url = cli.jellyfin_address.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+
+    // http(s) URLs always have a path, which `parse_url` ensures
+    url.path_segments_mut().unwrap().pop_if_empty().extend(path.split('/').filter(|it| !it.is_empty()));
+
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+
+    url
+}
+
+/// Built for every request, as the token can change at runtime
+pub fn headers(_cli: &Cli) -> HeaderMap {
     let mut headers = HeaderMap::new();
 
-    let auth_header = HeaderValue::from_str(&format!(r#"MediaBrowser Token="{}", Client="Jellyfin-Exporter", Version={}"#, cli.jellyfin_api_key, env!("CARGO_PKG_VERSION"))).unwrap();
+    let mut auth_header = HeaderValue::from_str(&format!(
+        r#"MediaBrowser Token="{}", Client="Jellyfin-Exporter", Device="Jellyfin-Exporter", DeviceId="{}", Version={}"#,
+        access_token().expose(),
+        DEVICE_ID,
+        env!("CARGO_PKG_VERSION")
+    ))
+    .unwrap();
+    auth_header.set_sensitive(true);
     headers.append("Authorization", auth_header);
     headers.append("Content-Type", HeaderValue::from_str("application/json").unwrap());
 
@@ -31,48 +124,48 @@ macro_rules! fatal_error {
     };
 }
 
-macro_rules! log_error {
-    ($it:expr, $err_text:expr) => {
-        $it.map_err(|e| {
-            warn!(concat!($err_text, ": {:?}"), e);
-            e
-        })
-    };
-}
-
 
-pub async fn handle_request(cli: &Cli, client: &Client, metrics: &mut Metrics) -> Result<(), reqwest::Error> {
-    let (is_up, config, users, devices, item_counts, sessions) =
-        tokio::join!(get_jellyfin_up(cli, client), get_jellyfin_config(cli, client), get_users(cli, client), get_devices(cli, client), get_item_counts(cli, client), get_sessions(cli, client));
-    fatal_error!(is_up, "Jellyfin Server is down!");
+/// With a `deadline`, collectors still running at that point are cancelled and the metrics collected so far are returned
+pub async fn handle_request(cli: &Cli, client: &Client, collectors: &[Box<dyn Collector>], metrics: &Metrics, deadline: Option<Instant>) -> Result<(), reqwest::Error> {
+    let timeout = deadline.map(|it| it.saturating_duration_since(Instant::now()));
+    fatal_error!(get_jellyfin_up(cli, client, metrics, timeout).await, "Jellyfin Server is down!");
     set_jellyfin_up(metrics);
 
-    if let Ok(config) = log_error!(config, "Could not get Jellyfin Info") {
-        set_config_metrics(&config, metrics)
-    };
+    run_collectors(collectors, cli, client, metrics, deadline).await;
 
-    if let Ok(sessions) = log_error!(sessions, "Could not get Sessions") {
-        set_session_metrics(&sessions, metrics)
+    if let Some(limit) = cli.jellyfin_exporter_max_series_per_metric {
+        limit_all_series(metrics, limit)
     }
 
-    if let Ok(devices) = log_error!(devices, "Could not get Devices") {
-        set_device_metrics(&devices, metrics)
-    }
 
-    if let Ok(item_counts) = log_error!(item_counts, "Could not get Item Counts") {
-        set_item_count_metrics(&item_counts, metrics)
-    }
+    Ok(())
+}
 
-    if let Ok(users) = log_error!(users, "Could not get Users") {
-        set_user_metrics(&users, metrics);
-        let items = get_items(cli, client, users, metrics).await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
 
-        for (user, items) in items {
-            validate_items(&items);
-            set_item_metrics(&items, metrics, &user)
-        }
-    };
+    fn url(address: &str, path: &str, query: &[(&str, &str)]) -> String {
+        let cli = Cli::try_parse_from(["jellyfin-exporter", "--jellyfin-address", address]).unwrap();
+        endpoint(&cli, path, query).to_string()
+    }
 
+    #[test]
+    fn keeps_the_base_path() {
+        assert_eq!(url("https://example.com/jellyfin", "/Sessions", &[]), "https://example.com/jellyfin/Sessions");
+        assert_eq!(url("https://example.com/jellyfin/", "/Sessions", &[]), "https://example.com/jellyfin/Sessions");
+        assert_eq!(url("https://example.com/jellyfin/", "/Users/AuthenticateByName", &[]), "https://example.com/jellyfin/Users/AuthenticateByName");
+    }
 
-    Ok(())
+    #[test]
+    fn works_without_a_base_path() {
+        assert_eq!(url("https://example.com", "/Sessions", &[]), "https://example.com/Sessions");
+        assert_eq!(url("https://example.com/", "/System/Info", &[]), "https://example.com/System/Info");
+    }
+
+    #[test]
+    fn encodes_the_query() {
+        assert_eq!(url("https://example.com/jellyfin?foo=bar#baz", "/Items", &[("UserId", "a b&c=d"), ("Recursive", "true")]), "https://example.com/jellyfin/Items?UserId=a+b%26c%3Dd&Recursive=true");
+    }
 }
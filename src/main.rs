@@ -1,37 +1,63 @@
-use crate::cli::Cli;
-use crate::http_client::{client, handle_request};
+use crate::circuit_breaker::{BreakerState, set_breaker_metrics};
+use crate::cli::Command;
+use crate::collector::enabled_collectors;
+use crate::config::load_cli;
+use crate::geoip::GeoIp;
+use crate::http_client::{client, tls_connector};
 use crate::metrics::register_metrics;
-use clap::Parser;
-use log::{debug, error, info};
-use std::net::SocketAddr;
-use std::time::Instant;
+use crate::server::{ServerState, serve};
+use log::{debug, info};
+use std::sync::Arc;
 
 mod api;
+mod cardinality;
+mod circuit_breaker;
 mod cli;
+mod collector;
+mod config;
+mod credentials;
+mod geoip;
 mod http_client;
 mod metrics;
+mod network;
+mod privacy;
+mod server;
+mod tracker;
+mod webhook;
+mod websocket;
 
 
 #[tokio::main]
 async fn main() {
-    let cli = Cli::parse();
+    let cli = load_cli();
     pretty_env_logger::init();
+    privacy::init(&cli).expect("Invalid privacy configuration");
+    credentials::load_credentials(&cli).expect("Could not load the credentials");
 
-    // TODO: This leaks the API key to stdout. Is this acceptable?
+    // Everything reading files given in the config is set up before `check-config` reports the config as valid
+    let client = client(&cli).expect("Could not build the HTTP client");
+    let geoip = GeoIp::open(&cli).expect("Could not open the GeoIP database").map(Arc::new);
+
+    if cli.command == Some(Command::CheckConfig) {
+        tls_connector(&cli).expect("Could not set up TLS for the Jellyfin WebSocket");
+        println!("Configuration is valid, effective configuration:\n{}", cli);
+        return;
+    }
+
+    // Secrets are wrapped in `Secret`, which only prints `<REDACTED>`
     info!("Jellyfin Exporter v{} starting...", env!("CARGO_PKG_VERSION"));
     debug!("Using options {}", cli);
 
-    let client = client(&cli);
-    let mut metrics = register_metrics();
-    let exporter = prometheus_exporter::start(SocketAddr::new(cli.jellyfin_exporter_address, cli.jellyfin_exporter_port)).expect("Failed to start the exporter!");
+    #[cfg(unix)]
+    tokio::spawn(credentials::reload_on_hangup(cli.clone()));
 
-    loop {
-        let _guard = exporter.wait_request();
-        let s = Instant::now();
-        if let Err(err) = handle_request(&cli, &client, &mut metrics).await {
-            error!("Failed to handle request: {:?}", err)
-        }
-
-        debug!("Done handling request in {:?}", Instant::now() - s)
+    let metrics = register_metrics();
+    set_breaker_metrics(BreakerState::Closed, &metrics);
+    let collectors = enabled_collectors(&cli, geoip);
+    info!("Enabled collectors: {}", collectors.iter().map(|it| it.name()).collect::<Vec<_>>().join(", "));
+    for collector in &collectors {
+        collector.start(&cli, &client, &metrics);
     }
+
+    serve(ServerState { cli, client, collectors, metrics, last_collection: Default::default() }).await.expect("Failed to start the exporter!");
 }
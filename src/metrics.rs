@@ -1,7 +1,17 @@
+use crate::cli::MetricLayout;
+use crate::geoip::Location;
+use crate::network::NetworkClass;
+use crate::privacy::apply;
 use chrono::{DateTime, Utc};
-use prometheus_exporter::prometheus::{IntGauge, IntGaugeVec, register_int_gauge, register_int_gauge_vec};
+use prometheus_exporter::prometheus::{
+    CounterVec, GaugeVec, Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, register_counter_vec, register_gauge_vec, register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec,
+};
+use jellyfin_exporter_derive::JellyfinMetric;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 
+#[derive(Clone)]
 pub struct Metrics {
     pub jellyfin_up: IntGauge,
     pub jellyfin_config: IntGaugeVec,
@@ -18,6 +28,58 @@ pub struct Metrics {
     pub jellyfin_items_season_user_data: IntGaugeVec,
     pub jellyfin_items_episode: IntGaugeVec,
     pub jellyfin_items_episode_user_data: IntGaugeVec,
+
+    pub jellyfin_series_seasons: IntGaugeVec,
+    pub jellyfin_series_episodes: IntGaugeVec,
+    pub jellyfin_series_runtime_seconds: IntGaugeVec,
+    pub jellyfin_series_episodes_watched: IntGaugeVec,
+    pub jellyfin_series_completion_ratio: GaugeVec,
+
+    pub jellyfin_item_info: IntGaugeVec,
+    pub jellyfin_item_production_year: IntGaugeVec,
+    pub jellyfin_item_community_rating: GaugeVec,
+    pub jellyfin_item_index_number: IntGaugeVec,
+    pub jellyfin_item_runtime_seconds: IntGaugeVec,
+    pub jellyfin_item_user_play_count: IntGaugeVec,
+    pub jellyfin_item_user_played: IntGaugeVec,
+    pub jellyfin_item_user_favorite: IntGaugeVec,
+    pub jellyfin_item_user_unplayed_item_count: IntGaugeVec,
+    pub jellyfin_item_user_played_percentage: GaugeVec,
+    pub jellyfin_item_user_last_played_timestamp_seconds: IntGaugeVec,
+
+    pub jellyfin_exporter_series_dropped: IntCounterVec,
+    pub jellyfin_exporter_collector_success: IntGaugeVec,
+    pub jellyfin_exporter_collector_duration_seconds: GaugeVec,
+
+    pub jellyfin_playback_seconds: CounterVec,
+    pub jellyfin_playback_starts: IntCounterVec,
+    pub jellyfin_playback_stops: IntCounterVec,
+    pub jellyfin_playback_duration_seconds: Histogram,
+    pub jellyfin_playback_stalled_sessions: IntGaugeVec,
+    pub jellyfin_playback_stalls: IntCounterVec,
+    pub jellyfin_transcode_speed: GaugeVec,
+    pub jellyfin_transcode_speed_average: GaugeVec,
+    pub jellyfin_sessions_peak_active: IntGauge,
+    pub jellyfin_sessions_peak_transcoding: IntGauge,
+    pub jellyfin_sessions_peak_remote: IntGauge,
+    pub jellyfin_sessions_network: IntGaugeVec,
+    pub jellyfin_sessions_network_bitrate_bits: IntGaugeVec,
+    pub jellyfin_sessions_country: IntGaugeVec,
+    pub jellyfin_session_location: IntGaugeVec,
+    pub jellyfin_device_location: IntGaugeVec,
+
+    pub jellyfin_exporter_scrapes: IntCounterVec,
+    pub jellyfin_exporter_http_retries: IntCounterVec,
+    pub jellyfin_exporter_http_timeouts: IntCounterVec,
+    pub jellyfin_exporter_circuit_breaker_state: IntGaugeVec,
+    pub jellyfin_exporter_circuit_breaker_opens: IntCounter,
+    pub jellyfin_exporter_collector_cached: IntGaugeVec,
+
+    pub jellyfin_exporter_websocket_connected: IntGauge,
+    pub jellyfin_exporter_websocket_reconnects: IntCounter,
+
+    pub jellyfin_webhook_events: IntCounterVec,
+    pub jellyfin_exporter_webhook_rejected: IntCounterVec,
 }
 
 pub fn register_metrics() -> Metrics {
@@ -36,180 +98,260 @@ pub fn register_metrics() -> Metrics {
         jellyfin_items_season_user_data: UserData::register("season"),
         jellyfin_items_episode: Episode::register(),
         jellyfin_items_episode_user_data: UserData::register("episode"),
+        jellyfin_series_seasons: register_int_gauge_vec!("jellyfin_series_seasons", "The number of seasons of a series", &["series_id", "series_name"]).unwrap(),
+        jellyfin_series_episodes: register_int_gauge_vec!("jellyfin_series_episodes", "The number of episodes of a series", &["series_id", "series_name"]).unwrap(),
+        jellyfin_series_runtime_seconds: register_int_gauge_vec!("jellyfin_series_runtime_seconds", "The total runtime of all episodes of a series", &["series_id", "series_name"]).unwrap(),
+        jellyfin_series_episodes_watched: register_int_gauge_vec!("jellyfin_series_episodes_watched", "The number of episodes of a series a user has watched", &[
+            "user_name", "user_id", "series_id", "series_name"
+        ])
+        .unwrap(),
+        jellyfin_series_completion_ratio: register_gauge_vec!("jellyfin_series_completion_ratio", "The ratio of watched episodes of a series for a user", &[
+            "user_name", "user_id", "series_id", "series_name"
+        ])
+        .unwrap(),
+        jellyfin_item_info: ItemInfo::register(),
+        jellyfin_item_production_year: register_int_gauge_vec!("jellyfin_item_production_year", "The production year of an item", &["item_id"]).unwrap(),
+        jellyfin_item_community_rating: register_gauge_vec!("jellyfin_item_community_rating", "The community rating of an item", &["item_id"]).unwrap(),
+        jellyfin_item_index_number: register_int_gauge_vec!("jellyfin_item_index_number", "The index number of a season or episode", &["item_id"]).unwrap(),
+        jellyfin_item_runtime_seconds: register_int_gauge_vec!("jellyfin_item_runtime_seconds", "The runtime of an item", &["item_id"]).unwrap(),
+        jellyfin_item_user_play_count: register_int_gauge_vec!("jellyfin_item_user_play_count", "How often a user has played an item", &["item_id", "user_id"]).unwrap(),
+        jellyfin_item_user_played: register_int_gauge_vec!("jellyfin_item_user_played", "Whether a user has played an item", &["item_id", "user_id"]).unwrap(),
+        jellyfin_item_user_favorite: register_int_gauge_vec!("jellyfin_item_user_favorite", "Whether a user has marked an item as favorite", &["item_id", "user_id"]).unwrap(),
+        jellyfin_item_user_unplayed_item_count: register_int_gauge_vec!("jellyfin_item_user_unplayed_item_count", "The number of unplayed children of an item", &["item_id", "user_id"]).unwrap(),
+        jellyfin_item_user_played_percentage: register_gauge_vec!("jellyfin_item_user_played_percentage", "How much of an item a user has played", &["item_id", "user_id"]).unwrap(),
+        jellyfin_item_user_last_played_timestamp_seconds: register_int_gauge_vec!("jellyfin_item_user_last_played_timestamp_seconds", "When a user has last played an item", &["item_id", "user_id"]).unwrap(),
+        jellyfin_exporter_series_dropped: register_int_counter_vec!("jellyfin_exporter_series_dropped_total", "Series dropped because a metric exceeded the configured series limit", &["metric"]).unwrap(),
+        jellyfin_exporter_collector_success: register_int_gauge_vec!("jellyfin_exporter_collector_success", "Whether a collector succeeded during the last scrape", &["collector"]).unwrap(),
+        jellyfin_exporter_collector_duration_seconds: register_gauge_vec!("jellyfin_exporter_collector_duration_seconds", "How long a collector took during the last scrape", &["collector"]).unwrap(),
+        jellyfin_playback_seconds: register_counter_vec!("jellyfin_playback_seconds_total", "Watch time derived from the playback position of active sessions", &["user", "client", "item_type", "play_method"]).unwrap(),
+        jellyfin_playback_starts: register_int_counter_vec!("jellyfin_playback_starts_total", "Playbacks started, including switching to another item", &["user", "client"]).unwrap(),
+        jellyfin_playback_stops: register_int_counter_vec!("jellyfin_playback_stops_total", "Playbacks stopped, including switching to another item", &["user", "client"]).unwrap(),
+        jellyfin_playback_duration_seconds: register_histogram!(
+            "jellyfin_playback_duration_seconds",
+            "How long an item was played before the playback stopped",
+            vec![60.0, 300.0, 600.0, 1200.0, 1800.0, 2700.0, 3600.0, 5400.0, 7200.0, 10800.0]
+        )
+        .unwrap(),
+        jellyfin_playback_stalled_sessions: register_int_gauge_vec!("jellyfin_playback_stalled_sessions", "Playing sessions whose position does not keep up with the wall-clock time", &["client", "play_method"]).unwrap(),
+        jellyfin_playback_stalls: register_int_counter_vec!("jellyfin_playback_stalls_total", "Times a playing session started to stall", &["client", "play_method"]).unwrap(),
+        jellyfin_transcode_speed: register_gauge_vec!("jellyfin_transcode_speed", "Effective transcode speed of a session as a multiple of realtime", &["session_id", "user", "client", "hardware_acceleration_type"]).unwrap(),
+        jellyfin_transcode_speed_average: register_gauge_vec!("jellyfin_transcode_speed_average", "Average effective transcode speed as a multiple of realtime", &["hardware_acceleration_type"]).unwrap(),
+        jellyfin_sessions_peak_active: register_int_gauge!("jellyfin_sessions_peak_active", "Peak number of concurrently playing sessions within the peak window").unwrap(),
+        jellyfin_sessions_peak_transcoding: register_int_gauge!("jellyfin_sessions_peak_transcoding", "Peak number of concurrent transcodes within the peak window").unwrap(),
+        jellyfin_sessions_peak_remote: register_int_gauge!("jellyfin_sessions_peak_remote", "Peak number of concurrently playing remote sessions within the peak window").unwrap(),
+        jellyfin_sessions_network: register_int_gauge_vec!("jellyfin_sessions_network", "Sessions by the network class of their remote address", &["network"]).unwrap(),
+        jellyfin_sessions_network_bitrate_bits: register_int_gauge_vec!("jellyfin_sessions_network_bitrate_bits", "Total bitrate in bits per second sent to the sessions of a network class", &["network"]).unwrap(),
+        jellyfin_sessions_country: register_int_gauge_vec!("jellyfin_sessions_country", "Sessions by the country of their remote address", &["country"]).unwrap(),
+        jellyfin_session_location: register_int_gauge_vec!("jellyfin_session_location", "Location of the remote address of a session, join on session_id", &["session_id", "country", "city", "asn"]).unwrap(),
+        jellyfin_device_location: register_int_gauge_vec!("jellyfin_device_location", "Location of the address a device was last seen from, join on device_id", &["device_id", "country", "city", "asn"]).unwrap(),
+        jellyfin_exporter_scrapes: register_int_counter_vec!("jellyfin_exporter_scrapes_total", "Scrapes by whether they collected, shared a concurrent collection or reused one within the minimum interval", &["collection"]).unwrap(),
+        jellyfin_exporter_http_retries: register_int_counter_vec!("jellyfin_exporter_http_retries_total", "Retried requests to the Jellyfin API", &["endpoint"]).unwrap(),
+        jellyfin_exporter_http_timeouts: register_int_counter_vec!("jellyfin_exporter_http_timeouts_total", "Requests to the Jellyfin API that timed out, including retried ones", &["endpoint"]).unwrap(),
+        jellyfin_exporter_circuit_breaker_state: register_int_gauge_vec!("jellyfin_exporter_circuit_breaker_state", "The state of the circuit breaker protecting Jellyfin, 1 for the current state", &["state"]).unwrap(),
+        jellyfin_exporter_circuit_breaker_opens: register_int_counter!("jellyfin_exporter_circuit_breaker_opens_total", "How often the circuit breaker opened").unwrap(),
+        jellyfin_exporter_collector_cached: register_int_gauge_vec!("jellyfin_exporter_collector_cached", "Whether a collector was skipped by the open circuit breaker and its metrics are from an earlier scrape", &["collector"]).unwrap(),
+        jellyfin_exporter_websocket_connected: register_int_gauge!("jellyfin_exporter_websocket_connected", "Whether the exporter is connected to the Jellyfin WebSocket").unwrap(),
+        jellyfin_exporter_websocket_reconnects: register_int_counter!("jellyfin_exporter_websocket_reconnects_total", "Reconnection attempts to the Jellyfin WebSocket").unwrap(),
+        jellyfin_webhook_events: register_int_counter_vec!("jellyfin_webhook_events_total", "Events received from the Jellyfin Webhook plugin", &["event", "user", "item_type", "client"]).unwrap(),
+        jellyfin_exporter_webhook_rejected: register_int_counter_vec!("jellyfin_exporter_webhook_rejected_total", "Webhook requests that were rejected", &["reason"]).unwrap(),
     }
 }
 
-pub fn set_jellyfin_up(metrics: &mut Metrics) {
+/// The metrics can only be registered once per process, so all tests share them
+#[cfg(test)]
+pub fn test_metrics() -> Metrics {
+    static METRICS: std::sync::OnceLock<Metrics> = std::sync::OnceLock::new();
+    METRICS.get_or_init(register_metrics).clone()
+}
+
+pub fn set_jellyfin_up(metrics: &Metrics) {
     metrics.jellyfin_up.set(1);
 }
 
 
 // This is the most efficient way of handling labeling as the `.with` and `HashMap` variant has "a much higher overhead"
 // The drawback, of course, is the ability to have an incorrect number of arguments or have them in the wrong order.
-// To avoid this, we tightly couple these two implementations and check in tests if the order and cardinality are correct.
-pub trait ExportableMetric {
-    fn set_metrics(&self, metrics: &mut Metrics);
+// To avoid this, `#[derive(JellyfinMetric)]` generates both the label names and the label values from the struct definition.
+pub trait LabelValue {
+    fn label_value(&self) -> String;
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+macro_rules! impl_label_value {
+    ($($it:ty),*) => {
+        $(impl LabelValue for $it {
+            fn label_value(&self) -> String {
+                self.to_string()
+            }
+        })*
+    };
+}
+
+impl_label_value!(String, &str, bool, i32, i64, f64, DateTime<Utc>);
+
+impl<T: LabelValue> LabelValue for Option<T> {
+    fn label_value(&self) -> String {
+        self.as_ref().map(|it| it.label_value()).unwrap_or("null".to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, JellyfinMetric)]
 #[serde(rename_all = "PascalCase")]
+#[metric(name = "jellyfin_users", help = "The registered Jellyfin users")]
 pub struct User {
+    #[metric(label, privacy = "user_name")]
     pub name: String,
+    #[metric(label, privacy = "user_id")]
     pub id:   String,
 
     // TODO: These are 60min wrong due to Jellyfin reporting as GMT. I'm not quite sure how to fix that yet.
+    #[metric(label)]
     pub last_login_date:    DateTime<Utc>,
+    #[metric(label)]
     pub last_activity_date: DateTime<Utc>,
     // TODO: other fields. I think only policy.is_administrator could be interesting
 }
 
-impl User {
-    pub fn register() -> IntGaugeVec {
-        register_int_gauge_vec!("jellyfin_users", "The registered Jellyfin users", &["name", "id", "last_login_date", "last_activity_date"]).unwrap()
-    }
-}
-
 
-pub fn set_user_metrics(users: &Vec<User>, metrics: &mut Metrics) {
+pub fn set_user_metrics(users: &Vec<User>, metrics: &Metrics) {
     metrics.jellyfin_users.reset();
 
     for user in users {
-        metrics.jellyfin_users.with_label_values(&[&user.name, &user.id, &user.last_activity_date.to_string(), &user.last_activity_date.to_string()]).set(1);
+        user.set_metric(&metrics.jellyfin_users);
     }
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, JellyfinMetric)]
 #[serde(rename_all = "PascalCase")]
+#[metric(name = "jellyfin_devices", help = "The devices of this Jellyfin instance")]
 pub struct Device {
+    #[metric(label, privacy = "device_name")]
     name: String,
-    id: String,
+    #[metric(label, privacy = "device_id")]
+    pub id: String,
+    #[metric(label, privacy = "user_name")]
     last_user_name: String,
+    #[metric(label, privacy = "user_id")]
     last_user_id: String,
 
+    #[metric(label)]
     app_name: String,
+    #[metric(label)]
     app_version: String,
+    #[metric(label)]
     date_last_activity: DateTime<Utc>,
 }
 
-impl Device {
-    pub fn register() -> IntGaugeVec {
-        register_int_gauge_vec!("jellyfin_devices", "The devices of this Jellyfin instance", &["name", "id", "last_user_name", "last_user_id", "app_name", "app_version", "date_last_activity"])
-            .unwrap()
-    }
-}
-
-pub fn set_device_metrics(devices: &Vec<Device>, metrics: &mut Metrics) {
+pub fn set_device_metrics(devices: &Vec<Device>, metrics: &Metrics) {
     metrics.jellyfin_devices.reset();
 
     for device in devices {
-        metrics
-            .jellyfin_devices
-            .with_label_values(&[&device.name, &device.id, &device.last_user_name, &device.last_user_id, &device.app_name, &device.app_version, &device.date_last_activity.to_string()])
-            .set(1);
+        device.set_metric(&metrics.jellyfin_devices);
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, JellyfinMetric)]
 #[serde(rename_all = "PascalCase")]
+#[metric(name = "jellyfin_sessions", help = "The sessions of this Jellyfin instance")]
 pub struct Session {
-    id: String,
-    user_id: String,
-    user_name: String,
-    server_id: String,
-    is_active: bool,
-
-    client: String,
-    device_name: String,
-    device_id: String,
-    application_version: String,
+    #[metric(label)]
+    pub id: String,
+    #[metric(label)]
+    pub user_id: String,
+    #[metric(label)]
+    pub user_name: String,
+    #[metric(label)]
+    pub server_id: String,
+    #[metric(label)]
+    pub is_active: bool,
+
+    #[metric(label)]
+    pub client: String,
+    #[metric(label)]
+    pub device_name: String,
+    #[metric(label)]
+    pub device_id: String,
+    #[metric(label)]
+    pub application_version: String,
+
+    #[metric(label)]
+    pub remote_end_point:   String,
+    #[serde(skip)]
+    #[metric(label)]
+    pub network: NetworkClass,
+    #[serde(skip)]
+    pub location: Option<Location>,
+    #[metric(label)]
+    pub last_activity_date: DateTime<Utc>,
 
-    remote_end_point:   String,
-    last_activity_date: DateTime<Utc>,
+    pub play_state: PlayState,
+    pub now_playing_item: Option<Item>,
+    pub transcoding_info: Option<TranscodingInfo>,
+}
 
-    play_state: PlayState,
-    now_playing_item: Option<Item>,
-    transcoding_info: Option<TranscodingInfo>,
+impl Session {
+    /// The bitrate sent to the client in bits per second: The transcoded bitrate, or the bitrate of the source when playing directly
+    pub fn bitrate(&self) -> Option<i64> {
+        match &self.transcoding_info {
+            Some(it) => Some(it.bitrate as i64),
+            None => self.now_playing_item.as_ref()?.bitrate(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct PlayState {
-    position_ticks: Option<i64>,
-    play_method: Option<String>,
-    is_paused: bool,
-    is_muted: bool,
-    volume_level: Option<i32>,
+    pub position_ticks: Option<i64>,
+    pub play_method: Option<String>,
+    pub is_paused: bool,
+    pub is_muted: bool,
+    pub volume_level: Option<i32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct TranscodingInfo {
-    audio_codec: String,
-    video_codec: String,
-    container: String,
-    is_video_direct: bool,
-    is_audio_direct: bool,
-    bitrate: i32,
-    framerate: Option<i32>,
-    completion_percentage: Option<f64>,
-    width: i32,
-    height: i32,
-    hardware_acceleration_type: Option<String>,
+    pub audio_codec: String,
+    pub video_codec: String,
+    pub container: String,
+    pub is_video_direct: bool,
+    pub is_audio_direct: bool,
+    pub bitrate: i32,
+    pub framerate: Option<i32>,
+    pub completion_percentage: Option<f64>,
+    pub width: i32,
+    pub height: i32,
+    pub hardware_acceleration_type: Option<String>,
 }
 
 
-impl Session {
-    pub fn register() -> IntGaugeVec {
-        register_int_gauge_vec!("jellyfin_sessions", "The sessions of this Jellyfin instance", &[
-            "id", "user_id", "user_name", "server_id", "is_active", "client", "device_name", "device_id", "application_version", "remote_end_point", "last_activity_date"
-        ])
-        .unwrap()
-    }
-}
-
-pub fn set_session_metrics(sessions: &Vec<Session>, metrics: &mut Metrics) {
+pub fn set_session_metrics(sessions: &Vec<Session>, metrics: &Metrics) {
     metrics.jellyfin_sessions.reset();
 
     for session in sessions {
-        metrics
-            .jellyfin_sessions
-            .with_label_values(&[
-                &session.id,
-                &session.user_id,
-                &session.user_name,
-                &session.server_id,
-                &session.is_active.to_string(),
-                &session.client,
-                &session.device_name,
-                &session.device_id,
-                &session.application_version,
-                &session.remote_end_point,
-                &session.last_activity_date.to_string(),
-            ])
-            .set(1);
+        session.set_metric(&metrics.jellyfin_sessions);
     }
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, JellyfinMetric)]
 #[serde(rename_all = "PascalCase")]
+#[metric(name = "jellyfin_config", help = "The configuration of this Jellyfin instance")]
 pub struct JellyfinConfig {
+    #[metric(label)]
     pub local_address: String,
+    #[metric(label)]
     pub server_name: String,
+    #[metric(label)]
     pub version: String,
+    #[metric(label)]
     pub id: String,
 }
 
-impl JellyfinConfig {
-    pub fn register() -> IntGaugeVec {
-        register_int_gauge_vec!("jellyfin_config", "The configuration of this Jellyfin instance", &["local_address", "server_name", "version", "id"]).unwrap()
-    }
-}
-
-pub fn set_config_metrics(config: &JellyfinConfig, metrics: &mut Metrics) {
+pub fn set_config_metrics(config: &JellyfinConfig, metrics: &Metrics) {
     metrics.jellyfin_config.reset();
-    metrics.jellyfin_config.with_label_values(&[&config.local_address, &config.server_name, &config.version, &config.id]).set(1);
+    config.set_metric(&metrics.jellyfin_config);
 }
 
 
@@ -236,7 +378,7 @@ impl ItemCounts {
     }
 }
 
-pub fn set_item_count_metrics(item_counts: &ItemCounts, metrics: &mut Metrics) {
+pub fn set_item_count_metrics(item_counts: &ItemCounts, metrics: &Metrics) {
     metrics.jellyfin_items_count.reset();
     metrics.jellyfin_items_count.with_label_values(&["Movie"]).set(item_counts.movie_count.unwrap_or(0));
     metrics.jellyfin_items_count.with_label_values(&["Series"]).set(item_counts.series_count.unwrap_or(0));
@@ -267,14 +409,94 @@ pub enum Item {
     // Not used
     Folder,
     ManualPlaylistsFolder,
+    #[serde(other)]
+    Other,
+}
+
+impl Item {
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Item::CollectionFolder(it) => Some(&it.id),
+            Item::Series(it) | Item::Movie(it) | Item::Book(it) => Some(&it.id),
+            Item::Season(it) => Some(&it.id),
+            Item::Episode(it) => Some(&it.id),
+            Item::Folder | Item::ManualPlaylistsFolder | Item::Other => None,
+        }
+    }
+
+    pub fn run_time_ticks(&self) -> Option<i64> {
+        match self {
+            Item::Series(it) | Item::Movie(it) | Item::Book(it) => it.run_time_ticks,
+            Item::Episode(it) => it.run_time_ticks,
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Item::CollectionFolder(_) => "CollectionFolder",
+            Item::Series(_) => "Series",
+            Item::Movie(_) => "Movie",
+            Item::Book(_) => "Book",
+            Item::Season(_) => "Season",
+            Item::Episode(_) => "Episode",
+            Item::Folder => "Folder",
+            Item::ManualPlaylistsFolder => "ManualPlaylistsFolder",
+            Item::Other => "Other",
+        }
+    }
+
+    /// The summed bitrate of all streams. Only known for items that are playing, the library does not request the streams.
+    pub fn bitrate(&self) -> Option<i64> {
+        let streams = match self {
+            Item::Series(it) | Item::Movie(it) | Item::Book(it) => &it.media_streams,
+            Item::Episode(it) => &it.media_streams,
+            _ => return None,
+        };
+
+        streams.iter().filter_map(|it| it.bit_rate).reduce(|a, b| a + b)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct MediaStream {
+    pub bit_rate: Option<i64>,
 }
 
-pub fn set_item_metrics(items: &Vec<Item>, metrics: &mut Metrics, user: &User) {
+// Items are set per user, so the reset has to happen once before iterating over all users
+pub fn reset_item_metrics(metrics: &Metrics) {
     metrics.jellyfin_items_library.reset();
     metrics.jellyfin_items_media_item.reset();
     metrics.jellyfin_items_season.reset();
     metrics.jellyfin_items_episode.reset();
+    metrics.jellyfin_series_seasons.reset();
+    metrics.jellyfin_series_episodes.reset();
+    metrics.jellyfin_series_runtime_seconds.reset();
+    metrics.jellyfin_series_episodes_watched.reset();
+    metrics.jellyfin_series_completion_ratio.reset();
+
+    metrics.jellyfin_item_info.reset();
+    metrics.jellyfin_item_production_year.reset();
+    metrics.jellyfin_item_community_rating.reset();
+    metrics.jellyfin_item_index_number.reset();
+    metrics.jellyfin_item_runtime_seconds.reset();
+    metrics.jellyfin_item_user_play_count.reset();
+    metrics.jellyfin_item_user_played.reset();
+    metrics.jellyfin_item_user_favorite.reset();
+    metrics.jellyfin_item_user_unplayed_item_count.reset();
+    metrics.jellyfin_item_user_played_percentage.reset();
+    metrics.jellyfin_item_user_last_played_timestamp_seconds.reset();
+}
+
+pub fn set_item_metrics(items: &Vec<Item>, metrics: &Metrics, user: &User, layout: MetricLayout) {
+    if layout == MetricLayout::Info {
+        for item in items {
+            set_item_info_metrics(item, metrics, user)
+        }
 
+        return;
+    }
 
     for item in items {
         match item {
@@ -286,144 +508,115 @@ pub fn set_item_metrics(items: &Vec<Item>, metrics: &mut Metrics, user: &User) {
             Item::Episode(it) => set_episode_metrics(it, metrics, user),
             Item::Folder => {}
             Item::ManualPlaylistsFolder => {}
+            Item::Other => {}
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, JellyfinMetric)]
 #[serde(rename_all = "PascalCase")]
+#[metric(name = "jellyfin_items_library", help = "The available Jellyfin libraries", extra_labels("user_name", "user_id"))]
 pub struct Library {
+    #[metric(label)]
     pub name: String,
+    #[metric(label)]
     pub server_id: String,
+    #[metric(label)]
     pub id: String,
+    #[metric(label)]
     pub collection_type: String, // TODO: Make this a better value, currently it is "movies", "tvshows", etc.
 
     pub user_data: Option<UserData>, // TODO: I would like to have the physical paths attached to this library
 }
 
 
-impl Library {
-    pub fn register() -> IntGaugeVec {
-        register_int_gauge_vec!("jellyfin_items_library", "The available Jellyfin libraries", &["user_name", "user_id", "name", "server_id", "id", "collection_type"]).unwrap()
-    }
-}
-
-macro_rules! to_nullable_string {
-    ($it:expr) => {
-        &$it.map(|it| it.to_string()).unwrap_or("null".to_string())
-    };
-}
-
-
-pub fn set_library_metrics(item: &Library, metrics: &mut Metrics, user: &User) {
-    metrics.jellyfin_items_library.with_label_values(&[&user.name, &user.id, &item.name, &item.server_id, &item.id, &item.collection_type]).set(1);
+pub fn set_library_metrics(item: &Library, metrics: &Metrics, user: &User) {
+    item.set_metric(&metrics.jellyfin_items_library, [&user.name, &user.id]);
 
     if let Some(it) = &item.user_data {
-        it.set_metrics(user, &metrics.jellyfin_items_library_user_data)
+        it.set_metric(&metrics.jellyfin_items_library_user_data, [&user.name, &user.id])
     };
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, JellyfinMetric)]
 #[serde(rename_all = "PascalCase")]
+#[metric(name = "jellyfin_items_media_item", help = "The available Jellyfin MediaItems (Series, Movies, Books)", extra_labels("user_name", "user_id"))]
 pub struct MediaItem {
+    #[metric(label)]
     pub name: String,
+    #[metric(label)]
     pub server_id: String,
+    #[metric(label)]
     pub id: String,
 
+    #[metric(label)]
     pub location_type: String,
+    #[metric(label)]
     pub media_type:    String,
 
+    #[metric(label)]
     pub premiere_date: Option<DateTime<Utc>>,
+    #[metric(label)]
     pub end_date: Option<DateTime<Utc>>,
+    #[metric(label)]
     pub production_year: Option<i32>,
+    #[metric(label)]
     pub official_rating: Option<String>,
+    #[metric(label)]
     pub community_rating: Option<f64>,
+    #[metric(label)]
     pub status: Option<String>,
+    pub run_time_ticks: Option<i64>,
+    #[serde(default)]
+    pub media_streams: Vec<MediaStream>,
 
     pub user_data: Option<UserData>,
 }
 
-impl MediaItem {
-    pub fn register() -> IntGaugeVec {
-        register_int_gauge_vec!("jellyfin_items_media_item", "The available Jellyfin MediaItems (Series, Movies, Books)", &[
-            "user_name", "user_id", "name", "server_id", "id", "location_type", "media_type", "premiere_date", "end_date", "production_year", "official_rating", "community_rating", "status"
-        ])
-        .unwrap()
-    }
-}
-
-pub fn set_media_item_metrics(item: &MediaItem, metrics: &mut Metrics, user: &User) {
-    metrics
-        .jellyfin_items_media_item
-        .with_label_values(&[
-            &user.name,
-            &user.id,
-            &item.name,
-            &item.server_id,
-            &item.id,
-            &item.location_type,
-            &item.media_type,
-            to_nullable_string!(item.premiere_date),
-            to_nullable_string!(item.end_date),
-            to_nullable_string!(item.production_year),
-            to_nullable_string!(item.official_rating.clone()),
-            to_nullable_string!(item.community_rating),
-            to_nullable_string!(item.status.clone()),
-        ])
-        .set(1);
+pub fn set_media_item_metrics(item: &MediaItem, metrics: &Metrics, user: &User) {
+    item.set_metric(&metrics.jellyfin_items_media_item, [&user.name, &user.id]);
 
     if let Some(it) = &item.user_data {
-        it.set_metrics(user, &metrics.jellyfin_items_media_item_user_data)
+        it.set_metric(&metrics.jellyfin_items_media_item_user_data, [&user.name, &user.id])
     };
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JellyfinMetric)]
 #[serde(rename_all = "PascalCase")]
+#[metric(name = "jellyfin_items_season", help = "The available Jellyfin seasons", extra_labels("user_name", "user_id"))]
 pub struct Season {
     pub series_name: String,
     pub series_id:   String,
 
+    #[metric(label)]
     pub name: String,
+    #[metric(label)]
     pub server_id: String,
+    #[metric(label)]
     pub id: String,
 
+    #[metric(label)]
     pub index_number:    Option<i32>,
+    #[metric(label)]
     pub premiere_date:   Option<DateTime<Utc>>, // Each season has its own premiere_date
+    #[metric(label)]
     pub production_year: Option<i32>,
 
     pub user_data: Option<UserData>,
 }
 
-impl Season {
-    pub fn register() -> IntGaugeVec {
-        register_int_gauge_vec!("jellyfin_items_season", "The available Jellyfin seasons", &["user_name", "user_id", "name", "server_id", "id", "index_number", "premiere_date", "production_year"])
-            .unwrap()
-    }
-}
-
-pub fn set_season_metrics(item: &Season, metrics: &mut Metrics, user: &User) {
-    metrics
-        .jellyfin_items_season
-        .with_label_values(&[
-            &user.name,
-            &user.id,
-            &item.name,
-            &item.server_id,
-            &item.id,
-            to_nullable_string!(item.index_number),
-            to_nullable_string!(item.premiere_date),
-            to_nullable_string!(item.production_year),
-        ])
-        .set(1);
+pub fn set_season_metrics(item: &Season, metrics: &Metrics, user: &User) {
+    item.set_metric(&metrics.jellyfin_items_season, [&user.name, &user.id]);
 
     if let Some(it) = &item.user_data {
-        it.set_metrics(user, &metrics.jellyfin_items_season_user_data)
+        it.set_metric(&metrics.jellyfin_items_season_user_data, [&user.name, &user.id])
     };
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JellyfinMetric)]
 #[serde(rename_all = "PascalCase")]
+#[metric(name = "jellyfin_items_episode", help = "The available Jellyfin episodes", extra_labels("user_name", "user_id"))]
 pub struct Episode {
     pub series_name: Option<String>,
     pub series_id:   Option<String>,
@@ -432,93 +625,414 @@ pub struct Episode {
     pub season_id: Option<String>,
     pub parent_index_number: Option<i32>,
 
+    #[metric(label)]
     pub name: String,
+    #[metric(label)]
     pub server_id: String,
+    #[metric(label)]
     pub id: String,
 
+    #[metric(label)]
     pub has_subtitles: Option<bool>,
+    #[metric(label)]
     pub container: Option<String>,
+    #[metric(label)]
     pub path: Option<String>,
     pub run_time_ticks: Option<i64>,
+    #[serde(default)]
+    pub media_streams: Vec<MediaStream>,
 
+    #[metric(label)]
     pub index_number:    Option<i32>,
+    #[metric(label)]
     pub premiere_date:   Option<DateTime<Utc>>, // Each season has its own premiere_date
+    #[metric(label)]
     pub production_year: Option<i32>,
 
     user_data: Option<UserData>,
 }
 
-impl Episode {
-    pub fn register() -> IntGaugeVec {
-        register_int_gauge_vec!("jellyfin_items_episode", "The available Jellyfin episodes", &[
-            "user_name", "user_id", "name", "server_id", "id", "has_subtitles", "container", "path", "index_number", "premiere_date", "production_year"
-        ])
-        .unwrap()
-    }
-}
-
-pub fn set_episode_metrics(item: &Episode, metrics: &mut Metrics, user: &User) {
-    metrics
-        .jellyfin_items_episode
-        .with_label_values(&[
-            &user.name,
-            &user.id,
-            &item.name,
-            &item.server_id,
-            &item.id,
-            to_nullable_string!(item.has_subtitles),
-            to_nullable_string!(item.container.clone()),
-            to_nullable_string!(item.path.clone()),
-            to_nullable_string!(item.index_number),
-            to_nullable_string!(item.premiere_date),
-            to_nullable_string!(item.production_year),
-        ])
-        .set(1);
+pub fn set_episode_metrics(item: &Episode, metrics: &Metrics, user: &User) {
+    item.set_metric(&metrics.jellyfin_items_episode, [&user.name, &user.id]);
 
     if let Some(it) = &item.user_data {
-        it.set_metrics(user, &metrics.jellyfin_items_episode_user_data)
+        it.set_metric(&metrics.jellyfin_items_episode_user_data, [&user.name, &user.id])
     };
 }
 
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "PascalCase")]
-pub struct ManualPlaylists {}
+/// The "info + value" layout: All descriptive attributes of an item are only exported once via `jellyfin_item_info`, while numeric values are keyed
+/// by `item_id` (and `user_id` for user data) alone. This keeps the number of label combinations bounded, the attributes can be joined on demand.
+#[derive(Debug, Default, JellyfinMetric)]
+#[metric(name = "jellyfin_item_info", help = "Descriptive attributes of a Jellyfin item, join on item_id")]
+pub struct ItemInfo<'a> {
+    #[metric(label)]
+    pub item_id: &'a str,
+    #[metric(label = "type")]
+    pub item_type: &'a str,
+    #[metric(label)]
+    pub name: &'a str,
+    #[metric(label)]
+    pub server_id: &'a str,
+
+    #[metric(label)]
+    pub series_id: Option<&'a str>,
+    #[metric(label)]
+    pub season_id: Option<&'a str>,
+    #[metric(label)]
+    pub collection_type: Option<&'a str>,
+    #[metric(label)]
+    pub location_type: Option<&'a str>,
+    #[metric(label)]
+    pub media_type: Option<&'a str>,
+    #[metric(label)]
+    pub premiere_date: Option<DateTime<Utc>>,
+    #[metric(label)]
+    pub end_date: Option<DateTime<Utc>>,
+    #[metric(label)]
+    pub official_rating: Option<&'a str>,
+    #[metric(label)]
+    pub status: Option<&'a str>,
+    #[metric(label)]
+    pub container: Option<&'a str>,
+    #[metric(label)]
+    pub path: Option<&'a str>,
+    #[metric(label)]
+    pub has_subtitles: Option<bool>,
+}
+
+fn set_item_values(id: &str, production_year: Option<i32>, community_rating: Option<f64>, index_number: Option<i32>, run_time_ticks: Option<i64>, metrics: &Metrics) {
+    if let Some(it) = production_year {
+        metrics.jellyfin_item_production_year.with_label_values(&[id]).set(it as i64);
+    }
+    if let Some(it) = community_rating {
+        metrics.jellyfin_item_community_rating.with_label_values(&[id]).set(it);
+    }
+    if let Some(it) = index_number {
+        metrics.jellyfin_item_index_number.with_label_values(&[id]).set(it as i64);
+    }
+    if let Some(it) = run_time_ticks {
+        metrics.jellyfin_item_runtime_seconds.with_label_values(&[id]).set(it / TICKS_PER_SECOND);
+    }
+}
 
+fn set_item_user_values(id: &str, user_data: &Option<UserData>, metrics: &Metrics, user: &User) {
+    let Some(user_data) = user_data else { return };
+    let user_id = apply("user_id", &user.id);
+    let labels = [id, &user_id];
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+    metrics.jellyfin_item_user_play_count.with_label_values(&labels).set(user_data.play_count as i64);
+    metrics.jellyfin_item_user_played.with_label_values(&labels).set(user_data.played as i64);
+    metrics.jellyfin_item_user_favorite.with_label_values(&labels).set(user_data.is_favorite as i64);
+
+    if let Some(it) = user_data.unplayed_item_count {
+        metrics.jellyfin_item_user_unplayed_item_count.with_label_values(&labels).set(it as i64);
+    }
+    if let Some(it) = user_data.played_percentage {
+        metrics.jellyfin_item_user_played_percentage.with_label_values(&labels).set(it);
+    }
+    if let Some(it) = user_data.last_played_date {
+        metrics.jellyfin_item_user_last_played_timestamp_seconds.with_label_values(&labels).set(it.timestamp());
+    }
+}
+
+pub fn set_item_info_metrics(item: &Item, metrics: &Metrics, user: &User) {
+    match item {
+        Item::CollectionFolder(it) => {
+            ItemInfo { item_id: &it.id, item_type: "CollectionFolder", name: &it.name, server_id: &it.server_id, collection_type: Some(&it.collection_type), ..Default::default() }
+                .set_metric(&metrics.jellyfin_item_info);
+            set_item_user_values(&it.id, &it.user_data, metrics, user);
+        }
+        Item::Series(it) | Item::Movie(it) | Item::Book(it) => {
+            ItemInfo {
+                item_id: &it.id,
+                item_type: item.type_name(),
+                name: &it.name,
+                server_id: &it.server_id,
+                location_type: Some(&it.location_type),
+                media_type: Some(&it.media_type),
+                premiere_date: it.premiere_date,
+                end_date: it.end_date,
+                official_rating: it.official_rating.as_deref(),
+                status: it.status.as_deref(),
+                ..Default::default()
+            }
+            .set_metric(&metrics.jellyfin_item_info);
+
+            set_item_values(&it.id, it.production_year, it.community_rating, None, it.run_time_ticks, metrics);
+            set_item_user_values(&it.id, &it.user_data, metrics, user);
+        }
+        Item::Season(it) => {
+            ItemInfo { item_id: &it.id, item_type: "Season", name: &it.name, server_id: &it.server_id, series_id: Some(&it.series_id), premiere_date: it.premiere_date, ..Default::default() }
+                .set_metric(&metrics.jellyfin_item_info);
+
+            set_item_values(&it.id, it.production_year, None, it.index_number, None, metrics);
+            set_item_user_values(&it.id, &it.user_data, metrics, user);
+        }
+        Item::Episode(it) => {
+            ItemInfo {
+                item_id: &it.id,
+                item_type: "Episode",
+                name: &it.name,
+                server_id: &it.server_id,
+                series_id: it.series_id.as_deref(),
+                season_id: it.season_id.as_deref(),
+                premiere_date: it.premiere_date,
+                container: it.container.as_deref(),
+                path: it.path.as_deref(),
+                has_subtitles: it.has_subtitles,
+                ..Default::default()
+            }
+            .set_metric(&metrics.jellyfin_item_info);
+
+            set_item_values(&it.id, it.production_year, None, it.index_number, it.run_time_ticks, metrics);
+            set_item_user_values(&it.id, &it.user_data, metrics, user);
+        }
+        Item::Folder => {}
+        Item::ManualPlaylistsFolder => {}
+        Item::Other => {}
+    }
+}
+
+
+/// Aggregated view of a series, built from the `Season` and `Episode` items that reference it via their `series_id`.
+#[derive(Debug, Default)]
+pub struct SeriesRollup<'a> {
+    pub name: &'a str,
+    pub season_ids: HashSet<&'a str>,
+    pub episodes: i64,
+    pub episodes_watched: i64,
+    pub run_time_ticks: i64,
+}
+
+impl SeriesRollup<'_> {
+    pub fn completion_ratio(&self) -> f64 {
+        if self.episodes == 0 {
+            return 0.0;
+        }
+
+        self.episodes_watched as f64 / self.episodes as f64
+    }
+}
+
+// Jellyfin ticks are 100ns intervals
+pub const TICKS_PER_SECOND: i64 = 10_000_000;
+
+pub fn collect_series_rollups(items: &[Item]) -> HashMap<&str, SeriesRollup<'_>> {
+    let mut series = HashMap::<&str, SeriesRollup>::new();
+
+    for item in items {
+        match item {
+            Item::Series(it) => series.entry(&it.id).or_default().name = &it.name,
+            Item::Season(it) => {
+                let rollup = series.entry(&it.series_id).or_default();
+                rollup.name = &it.series_name;
+                rollup.season_ids.insert(&it.id);
+            }
+            Item::Episode(it) => {
+                let Some(series_id) = &it.series_id else { continue };
+                let rollup = series.entry(series_id).or_default();
+
+                if let Some(name) = &it.series_name {
+                    rollup.name = name;
+                }
+                if let Some(season_id) = &it.season_id {
+                    rollup.season_ids.insert(season_id);
+                }
+
+                rollup.episodes += 1;
+                rollup.run_time_ticks += it.run_time_ticks.unwrap_or(0);
+                rollup.episodes_watched += it.user_data.as_ref().is_some_and(|it| it.played) as i64;
+            }
+            _ => {}
+        }
+    }
+
+    series
+}
+
+pub fn set_series_metrics(items: &[Item], metrics: &Metrics, user: &User) {
+    let (user_name, user_id) = (apply("user_name", &user.name), apply("user_id", &user.id));
+
+    for (series_id, rollup) in collect_series_rollups(items) {
+        // The library-wide values are the same for every user, setting them multiple times is harmless
+        metrics.jellyfin_series_seasons.with_label_values(&[series_id, rollup.name]).set(rollup.season_ids.len() as i64);
+        metrics.jellyfin_series_episodes.with_label_values(&[series_id, rollup.name]).set(rollup.episodes);
+        metrics.jellyfin_series_runtime_seconds.with_label_values(&[series_id, rollup.name]).set(rollup.run_time_ticks / TICKS_PER_SECOND);
+
+        metrics.jellyfin_series_episodes_watched.with_label_values(&[&user_name, &user_id, series_id, rollup.name]).set(rollup.episodes_watched);
+        metrics.jellyfin_series_completion_ratio.with_label_values(&[&user_name, &user_id, series_id, rollup.name]).set(rollup.completion_ratio());
+    }
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, JellyfinMetric)]
 #[serde(rename_all = "PascalCase")]
+#[metric(extra_labels("user_name", "user_id"))]
 pub struct UserData {
+    #[metric(label)]
     pub play_count:  i32, // Series never have a play count, only episodes do.
+    #[metric(label)]
     pub played:      bool,
+    #[metric(label)]
     pub is_favorite: bool,
 
+    #[metric(label)]
     pub unplayed_item_count: Option<i32>,
+    #[metric(label)]
     pub last_played_date:    Option<DateTime<Utc>>,
+    #[metric(label)]
     pub played_percentage:   Option<f64>,
 }
 
 impl UserData {
     pub fn register(name: &str) -> IntGaugeVec {
-        register_int_gauge_vec!(&format!("jellyfin_items_{}_user_data", name), "User Data of Libraries", &[
-            "user_name", "user_id", "play_count", "played", "is_favorite", "unplayed_item_count", "last_played_date", "played_percentage"
-        ])
-        .unwrap()
-    }
-
-    pub fn set_metrics(&self, user: &User, gauge: &IntGaugeVec) {
-        // TODO: This is the wrong IntGaugeVec
-        gauge
-            .with_label_values(&[
-                &user.name,
-                &user.id,
-                &self.play_count.to_string(),
-                &self.played.to_string(),
-                &self.is_favorite.to_string(),
-                to_nullable_string!(self.unplayed_item_count),
-                to_nullable_string!(self.last_played_date),
-                to_nullable_string!(self.played_percentage),
-            ])
-            .set(1);
+        register_int_gauge_vec!(&format!("jellyfin_items_{}_user_data", name), "User Data of Libraries", Self::LABELS).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+    use clap::Parser;
+    use prometheus_exporter::prometheus::Opts;
+    use std::sync::Once;
+
+    // The privacy rules are global, every test of this binary sees the same ones
+    fn init_privacy() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            let cli = Cli::try_parse_from(["jellyfin-exporter", "--jellyfin-address", "http://localhost:8096", "--jellyfin-exporter-privacy", "device_name=truncate:3,user_name=drop"]).unwrap();
+            crate::privacy::init(&cli).unwrap();
+        });
+    }
+
+    fn date() -> DateTime<Utc> {
+        "2024-05-06T07:08:09Z".parse().unwrap()
+    }
+
+    fn labeled<'a>(labels: &[&'a str], values: &'a [String]) -> Vec<(&'a str, &'a str)> {
+        labels.iter().copied().zip(values.iter().map(String::as_str)).collect()
+    }
+
+    #[test]
+    fn session_labels_match_values() {
+        init_privacy();
+        let session = Session {
+            id: "session".to_string(),
+            user_id: "u1".to_string(),
+            user_name: "alice".to_string(),
+            server_id: "server".to_string(),
+            is_active: true,
+            client: "Jellyfin Web".to_string(),
+            device_name: "Living Room TV".to_string(),
+            device_id: "d1".to_string(),
+            application_version: "10.10.0".to_string(),
+            remote_end_point: "192.168.1.2".to_string(),
+            network: NetworkClass::Remote,
+            last_activity_date: date(),
+            ..Default::default()
+        };
+
+        let values = session.label_values();
+        assert_eq!(Session::LABELS.len(), values.len());
+        assert_eq!(labeled(Session::LABELS, &values), [
+            ("id", "session"),
+            ("user_id", "u1"),
+            ("user_name", ""),
+            ("server_id", "server"),
+            ("is_active", "true"),
+            ("client", "Jellyfin Web"),
+            ("device_name", "Liv"),
+            ("device_id", "d1"),
+            ("application_version", "10.10.0"),
+            ("remote_end_point", "192.168.1.2"),
+            ("network", "remote"),
+            ("last_activity_date", date().to_string().as_str()),
+        ]);
+    }
+
+    #[test]
+    fn episode_labels_follow_extra_labels() {
+        init_privacy();
+        let episode = Episode {
+            series_name: Some("Series".to_string()),
+            series_id: Some("series".to_string()),
+            season_name: None,
+            season_id: None,
+            parent_index_number: Some(1),
+            name: "Pilot".to_string(),
+            server_id: "server".to_string(),
+            id: "episode".to_string(),
+            has_subtitles: Some(false),
+            container: None,
+            path: Some("/media/pilot.mkv".to_string()),
+            run_time_ticks: None,
+            media_streams: vec![],
+            index_number: Some(1),
+            premiere_date: None,
+            production_year: Some(2024),
+            user_data: None,
+        };
+
+        let values = episode.label_values();
+        assert_eq!(&Episode::LABELS[..2], ["user_name", "user_id"]);
+        assert_eq!(Episode::LABELS.len(), 2 + values.len());
+        assert_eq!(labeled(&Episode::LABELS[2..], &values), [
+            ("name", "Pilot"),
+            ("server_id", "server"),
+            ("id", "episode"),
+            ("has_subtitles", "false"),
+            ("container", "null"),
+            ("path", "/media/pilot.mkv"),
+            ("index_number", "1"),
+            ("premiere_date", "null"),
+            ("production_year", "2024"),
+        ]);
+    }
+
+    #[test]
+    fn user_data_sets_extra_labels_first() {
+        init_privacy();
+        let user_data = UserData { play_count: 3, played: true, is_favorite: false, unplayed_item_count: None, last_played_date: Some(date()), played_percentage: Some(12.5) };
+
+        let values = user_data.label_values();
+        assert_eq!(&UserData::LABELS[..2], ["user_name", "user_id"]);
+        assert_eq!(UserData::LABELS.len(), 2 + values.len());
+
+        let gauge = IntGaugeVec::new(Opts::new("test_user_data", "test"), UserData::LABELS).unwrap();
+        user_data.set_metric(&gauge, ["alice", "u1"]);
+
+        // The extra `user_name` is dropped by the privacy rule like an own label would be
+        let last_played = date().to_string();
+        assert_eq!(gauge.get_metric_with_label_values(&["", "u1", "3", "true", "false", "null", &last_played, "12.5"]).unwrap().get(), 1);
+    }
+
+    #[test]
+    fn renamed_labels_keep_their_name() {
+        init_privacy();
+        let info = ItemInfo { item_id: "item", item_type: "Movie", name: "Movie", server_id: "server", ..Default::default() };
+
+        assert_eq!(&ItemInfo::LABELS[..4], ["item_id", "type", "name", "server_id"]);
+        assert_eq!(&info.label_values()[..4], ["item", "Movie", "Movie", "server"]);
+    }
+
+    #[test]
+    fn privacy_names_apply_the_rule_of_another_label() {
+        init_privacy();
+        let device = Device {
+            name: "Living Room TV".to_string(),
+            id: "d1".to_string(),
+            last_user_name: "alice".to_string(),
+            last_user_id: "u1".to_string(),
+            app_name: "Jellyfin Web".to_string(),
+            app_version: "10.10.0".to_string(),
+            date_last_activity: date(),
+        };
+
+        // The label names stay the field names, the values are handled like `device_name` and `user_name`
+        let values = device.label_values();
+        assert_eq!(labeled(&Device::LABELS[..4], &values[..4]), [("name", "Liv"), ("id", "d1"), ("last_user_name", ""), ("last_user_id", "u1")]);
     }
 }
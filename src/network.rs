@@ -0,0 +1,101 @@
+use crate::cli::Cli;
+use crate::geoip::GeoIp;
+use crate::metrics::{LabelValue, Metrics, Session};
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Where a session connects from, based on the configured local and VPN networks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NetworkClass {
+    #[default]
+    Local,
+    Vpn,
+    Remote,
+}
+
+impl NetworkClass {
+    pub const ALL: [NetworkClass; 3] = [NetworkClass::Local, NetworkClass::Vpn, NetworkClass::Remote];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NetworkClass::Local => "local",
+            NetworkClass::Vpn => "vpn",
+            NetworkClass::Remote => "remote",
+        }
+    }
+}
+
+impl LabelValue for NetworkClass {
+    fn label_value(&self) -> String {
+        self.as_str().to_string()
+    }
+}
+
+#[derive(Clone)]
+pub struct NetworkClassifier {
+    local: Vec<IpNet>,
+    vpn: Vec<IpNet>,
+    drop_remote_address: bool,
+    geoip: Option<Arc<GeoIp>>,
+}
+
+impl NetworkClassifier {
+    pub fn new(cli: &Cli, geoip: Option<Arc<GeoIp>>) -> Self {
+        NetworkClassifier { local: cli.jellyfin_exporter_local_networks.clone(), vpn: cli.jellyfin_exporter_vpn_networks.clone(), drop_remote_address: cli.jellyfin_exporter_drop_remote_address, geoip }
+    }
+
+    pub fn geoip(&self) -> Option<&GeoIp> {
+        self.geoip.as_deref()
+    }
+
+    pub fn classify(&self, ip: IpAddr) -> NetworkClass {
+        if self.vpn.iter().any(|it| it.contains(&ip)) {
+            NetworkClass::Vpn
+        } else if self.local.iter().any(|it| it.contains(&ip)) {
+            NetworkClass::Local
+        } else {
+            NetworkClass::Remote
+        }
+    }
+
+    /// Sets the network class and location of every session. Has to happen before anything else looks at the sessions, as the raw address may be dropped here.
+    pub fn classify_sessions(&self, sessions: &mut [Session]) {
+        for session in sessions {
+            // Jellyfin does not always report an address, e.g. for sessions of the server itself. These were never considered remote.
+            // Dual-stack sockets report IPv4 clients as `::ffff:a.b.c.d`
+            let ip = session.remote_end_point.parse::<IpAddr>().ok().map(|it| it.to_canonical());
+            session.network = ip.map(|it| self.classify(it)).unwrap_or_default();
+
+            if let (Some(geoip), Some(ip)) = (&self.geoip, ip) {
+                session.location = geoip.lookup(ip);
+
+                if let Some(location) = &session.location {
+                    geoip.remember_device(&session.device_id, location);
+                }
+            }
+
+            if self.drop_remote_address {
+                session.remote_end_point.clear();
+            }
+        }
+    }
+}
+
+pub fn set_network_metrics(sessions: &[Session], metrics: &Metrics) {
+    let mut by_network = HashMap::<NetworkClass, (i64, i64)>::new();
+
+    for session in sessions {
+        let (count, bitrate) = by_network.entry(session.network).or_default();
+        *count += 1;
+        *bitrate += session.bitrate().unwrap_or(0);
+    }
+
+    // Every class is always exported, so an idle class is 0 instead of missing
+    for network in NetworkClass::ALL {
+        let (count, bitrate) = by_network.get(&network).copied().unwrap_or_default();
+        metrics.jellyfin_sessions_network.with_label_values(&[network.as_str()]).set(count);
+        metrics.jellyfin_sessions_network_bitrate_bits.with_label_values(&[network.as_str()]).set(bitrate);
+    }
+}
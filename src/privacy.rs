@@ -0,0 +1,149 @@
+use crate::cli::Cli;
+use crate::metrics::{Device, Episode, ItemInfo, JellyfinConfig, Library, MediaItem, Season, Session, User, UserData};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+// 64 bits of the hash are plenty to keep the values distinct while keeping the labels short
+const HASH_LENGTH: usize = 16;
+
+static PRIVACY: OnceLock<Privacy> = OnceLock::new();
+
+// Labels set by hand with `apply` instead of through `#[derive(JellyfinMetric)]`, e.g. of the playback counters and the device locations
+const MANUAL_LABELS: &[&str] = &["user_name", "user_id", "client", "device_id"];
+
+/// What happens to the value of a label before it is exported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrivacyAction {
+    /// Exports an empty value, which Prometheus treats like a missing label
+    Drop,
+    /// Keeps the first n characters
+    Truncate(usize),
+    /// Replaces the value with a salted hash, so series of the same value can still be correlated
+    Hash,
+}
+
+/// `<label>=drop`, `<label>=truncate:<n>` or `<label>=hash`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrivacyRule {
+    pub label: String,
+    pub action: PrivacyAction,
+}
+
+impl FromStr for PrivacyRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (label, action) = s.split_once('=').ok_or(format!("expected <label>=<action>, got `{s}`"))?;
+
+        let action = match action.split_once(':') {
+            None if action == "drop" => PrivacyAction::Drop,
+            None if action == "hash" => PrivacyAction::Hash,
+            Some(("truncate", length)) => PrivacyAction::Truncate(length.parse().map_err(|e| format!("invalid length `{length}`: {e}"))?),
+            _ => return Err(format!("unknown action `{action}`, expected drop, truncate:<n> or hash")),
+        };
+
+        Ok(PrivacyRule { label: label.to_string(), action })
+    }
+}
+
+impl Display for PrivacyRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.action {
+            PrivacyAction::Drop => write!(f, "{}=drop", self.label),
+            PrivacyAction::Truncate(length) => write!(f, "{}=truncate:{}", self.label, length),
+            PrivacyAction::Hash => write!(f, "{}=hash", self.label),
+        }
+    }
+}
+
+struct Privacy {
+    actions: HashMap<String, PrivacyAction>,
+    salt: String,
+}
+
+/// Has to be called before the first metric is set, later calls are ignored
+pub fn init(cli: &Cli) -> Result<(), String> {
+    let rules = &cli.jellyfin_exporter_privacy;
+
+    // Without a secret salt, hashed user names can simply be looked up by hashing all known names
+    if rules.iter().any(|it| it.action == PrivacyAction::Hash) && cli.jellyfin_exporter_privacy_salt.as_ref().is_none_or(|it| it.expose().is_empty()) {
+        return Err("hashing labels requires --jellyfin-exporter-privacy-salt".to_string());
+    }
+
+    // A typo in a rule would silently export the value unchanged
+    let labels = known_labels();
+    if let Some(rule) = rules.iter().find(|it| !labels.contains(it.label.as_str())) {
+        return Err(format!("unknown label `{}` in --jellyfin-exporter-privacy, expected one of {}", rule.label, labels.into_iter().collect::<Vec<_>>().join(", ")));
+    }
+
+    let actions = rules.iter().map(|it| (it.label.clone(), it.action)).collect();
+    let _ = PRIVACY.set(Privacy { actions, salt: cli.jellyfin_exporter_privacy_salt.as_ref().map(|it| it.expose().to_string()).unwrap_or_default() });
+
+    Ok(())
+}
+
+/// All names `apply` is called with
+fn known_labels() -> BTreeSet<&'static str> {
+    [
+        User::PRIVACY_LABELS,
+        Device::PRIVACY_LABELS,
+        Session::PRIVACY_LABELS,
+        JellyfinConfig::PRIVACY_LABELS,
+        Library::PRIVACY_LABELS,
+        MediaItem::PRIVACY_LABELS,
+        Season::PRIVACY_LABELS,
+        Episode::PRIVACY_LABELS,
+        ItemInfo::PRIVACY_LABELS,
+        UserData::PRIVACY_LABELS,
+        MANUAL_LABELS,
+    ]
+    .concat()
+    .into_iter()
+    .collect()
+}
+
+/// Applies the configured rule to a label value. Labels holding the same data under another name (e.g. `user`) pass the name of the rule instead.
+pub fn apply<'a>(label: &str, value: &'a str) -> Cow<'a, str> {
+    let Some(privacy) = PRIVACY.get() else { return Cow::Borrowed(value) };
+
+    match privacy.actions.get(label) {
+        None => Cow::Borrowed(value),
+        Some(_) if value.is_empty() => Cow::Borrowed(value),
+        Some(PrivacyAction::Drop) => Cow::Borrowed(""),
+        Some(PrivacyAction::Truncate(length)) => match value.char_indices().nth(*length) {
+            Some((end, _)) => Cow::Borrowed(&value[..end]),
+            None => Cow::Borrowed(value),
+        },
+        Some(PrivacyAction::Hash) => {
+            let hash = Sha256::new().chain_update(&privacy.salt).chain_update([0]).chain_update(value).finalize();
+            Cow::Owned(hash.iter().map(|it| format!("{it:02x}")).collect::<String>()[..HASH_LENGTH].to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn rejects_unknown_labels() {
+        // Fails before the rules are stored, so the other tests are not affected
+        let cli = Cli::try_parse_from(["jellyfin-exporter", "--jellyfin-address", "http://localhost:8096", "--jellyfin-exporter-privacy", "user_name=drop,usr_name=drop"]).unwrap();
+        assert!(init(&cli).unwrap_err().starts_with("unknown label `usr_name`"));
+    }
+
+    #[test]
+    fn knows_derived_and_manual_labels() {
+        let labels = known_labels();
+
+        for it in ["user_name", "user_id", "device_name", "device_id", "client", "path", "remote_end_point", "type"] {
+            assert!(labels.contains(it), "{it}");
+        }
+        assert!(!labels.contains("usr_name"));
+    }
+}
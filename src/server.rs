@@ -0,0 +1,101 @@
+use crate::cli::Cli;
+use crate::collector::Collector;
+use crate::http_client::handle_request;
+use crate::metrics::Metrics;
+use crate::webhook::handle_webhook;
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::header::{CONTENT_TYPE, LOCATION};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use log::{debug, error, info};
+use prometheus_exporter::prometheus::{Encoder, TextEncoder};
+use reqwest::Client;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const METRICS_PATH: &str = "/metrics";
+const SCRAPE_TIMEOUT_HEADER: &str = "X-Prometheus-Scrape-Timeout-Seconds";
+
+pub struct ServerState {
+    pub cli: Cli,
+    pub client: Client,
+    pub collectors: Vec<Box<dyn Collector>>,
+    pub metrics: Metrics,
+
+    // Scrapes are handled one at a time, the collectors keep state between collections. Holds when the last collection finished.
+    pub last_collection: Mutex<Option<Instant>>,
+}
+
+pub async fn serve(state: ServerState) -> std::io::Result<()> {
+    let address = SocketAddr::new(state.cli.jellyfin_exporter_address, state.cli.jellyfin_exporter_port);
+    let mut router = Router::new().route(METRICS_PATH, get(metrics));
+
+    if state.cli.jellyfin_exporter_webhook {
+        router = router.route("/webhook", post(webhook));
+    }
+
+    let router = router.fallback(redirect).with_state(Arc::new(state));
+    let listener = tokio::net::TcpListener::bind(address).await?;
+
+    info!("Exporting metrics to http://{}{}", address, METRICS_PATH);
+    axum::serve(listener, router).await
+}
+
+async fn metrics(State(state): State<Arc<ServerState>>, headers: HeaderMap) -> Response {
+    let arrived = Instant::now();
+    let deadline = scrape_timeout(&headers).map(|it| tokio::time::Instant::from_std(arrived) + it.saturating_sub(Duration::from_secs_f64(state.cli.jellyfin_exporter_scrape_timeout_offset)));
+    let mut last_collection = state.last_collection.lock().await;
+
+    match *last_collection {
+        // Another scrape collected while this one waited for the lock, e.g. a second Prometheus replica
+        Some(it) if it > arrived => {
+            debug!("Sharing the collection of a concurrent scrape");
+            state.metrics.jellyfin_exporter_scrapes.with_label_values(&["coalesced"]).inc();
+        }
+        Some(it) if it.elapsed() < Duration::from_secs(state.cli.jellyfin_exporter_min_interval) => {
+            debug!("Reusing the collection from {:?} ago", it.elapsed());
+            state.metrics.jellyfin_exporter_scrapes.with_label_values(&["reused"]).inc();
+        }
+        _ => {
+            let s = Instant::now();
+
+            if let Err(err) = handle_request(&state.cli, &state.client, &state.collectors, &state.metrics, deadline).await {
+                error!("Failed to handle request: {:?}", err)
+            }
+
+            debug!("Done handling request in {:?}", Instant::now() - s);
+            state.metrics.jellyfin_exporter_scrapes.with_label_values(&["collected"]).inc();
+            *last_collection = Some(Instant::now());
+        }
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+
+    match encoder.encode(&prometheus_exporter::prometheus::gather(), &mut buffer) {
+        Ok(()) => ([(CONTENT_TYPE, encoder.format_type().to_string())], buffer).into_response(),
+        Err(e) => {
+            error!("Failed to encode the metrics: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// Prometheus sends the scrape timeout of the job, after which it aborts the scrape and discards everything
+fn scrape_timeout(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers.get(SCRAPE_TIMEOUT_HEADER)?.to_str().ok()?.parse::<f64>().ok()?;
+    Duration::try_from_secs_f64(seconds).ok().filter(|it| !it.is_zero())
+}
+
+async fn webhook(State(state): State<Arc<ServerState>>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    handle_webhook(&state.cli, &state.metrics, &headers, &body)
+}
+
+async fn redirect() -> impl IntoResponse {
+    (StatusCode::MOVED_PERMANENTLY, [(LOCATION, METRICS_PATH)], format!("try {} for metrics\n", METRICS_PATH))
+}
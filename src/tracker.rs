@@ -0,0 +1,326 @@
+use crate::metrics::{Metrics, Session, TICKS_PER_SECOND};
+use crate::network::NetworkClass;
+use crate::privacy::apply;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// Seeking forward moves the position without anyone watching. Anything faster than this is not counted as watch time.
+const MAX_PLAYBACK_SPEED: f64 = 2.0;
+
+// Clients only report their position every few seconds, so the progress has to be judged over a longer window than a single scrape interval
+const STALL_WINDOW: Duration = Duration::from_secs(30);
+// A playing session that advances slower than this fraction of the wall-clock time is considered stalled
+const STALL_MIN_PROGRESS: f64 = 0.5;
+
+/// The state of a session at the last collection
+#[derive(Debug, Clone)]
+pub struct TrackedSession {
+    pub user_name: String,
+    pub client: String,
+
+    pub seen_at: Instant,
+    pub item_id: Option<String>,
+    pub position_ticks: Option<i64>,
+    pub is_paused: bool,
+
+    // When the current item started playing, `None` if nothing is playing or it was already playing when the exporter started
+    pub playing_since: Option<Instant>,
+
+    // The start of the window used for the stall detection
+    pub window_start: Instant,
+    pub window_ticks: Option<i64>,
+    pub stalled: bool,
+
+    pub transcode_completion: Option<f64>,
+}
+
+impl TrackedSession {
+    pub fn new(session: &Session, now: Instant) -> Self {
+        let item_id = session.now_playing_item.as_ref().map(|it| it.id().unwrap_or_default().to_string());
+
+        TrackedSession {
+            user_name: session.user_name.clone(),
+            client: session.client.clone(),
+            seen_at: now,
+            playing_since: item_id.as_ref().map(|_| now),
+            item_id,
+            position_ticks: session.play_state.position_ticks,
+            is_paused: session.play_state.is_paused,
+            window_start: now,
+            window_ticks: session.play_state.position_ticks,
+            stalled: false,
+            transcode_completion: session.transcoding_info.as_ref().and_then(|it| it.completion_percentage),
+        }
+    }
+
+    /// Carries over the stall window of the previous collection of the same item, evaluating it once it is long enough
+    fn update_stall_state(&mut self, previous: &TrackedSession) {
+        // A paused or idle session is never stalled, its window starts again once it resumes
+        if self.is_paused || self.item_id.is_none() {
+            return;
+        }
+
+        let elapsed = self.seen_at.duration_since(previous.window_start);
+        if elapsed < STALL_WINDOW {
+            self.window_start = previous.window_start;
+            self.window_ticks = previous.window_ticks;
+            self.stalled = previous.stalled;
+            return;
+        }
+
+        // Without a reported position there is nothing to judge the progress by
+        if let (Some(start), Some(end)) = (previous.window_ticks, self.position_ticks) {
+            self.stalled = ((end - start) as f64 / TICKS_PER_SECOND as f64 / elapsed.as_secs_f64()) < STALL_MIN_PROGRESS;
+        }
+    }
+}
+
+/// The number of concurrent streams at a single collection
+#[derive(Debug, Clone, Copy)]
+pub struct ConcurrencySample {
+    pub seen_at: Instant,
+    pub active: i64,
+    pub transcoding: i64,
+    pub remote: i64,
+}
+
+/// Turns the point-in-time session snapshots into counters by diffing them against the previous collection.
+#[derive(Debug, Default)]
+pub struct SessionTracker {
+    pub sessions: HashMap<String, TrackedSession>,
+
+    // Playback that is already running when the exporter starts is not counted as a start
+    pub initialized: bool,
+
+    pub peak_window: Duration,
+    pub samples: VecDeque<ConcurrencySample>,
+}
+
+impl SessionTracker {
+    pub fn new(peak_window: Duration) -> Self {
+        SessionTracker { peak_window, ..Default::default() }
+    }
+
+    pub fn update(&mut self, sessions: &[Session], now: Instant, metrics: &Metrics) {
+        let mut tracked = HashMap::with_capacity(sessions.len());
+        let mut transcode_speeds = Vec::new();
+
+        for session in sessions {
+            let mut current = TrackedSession::new(session, now);
+
+            match self.sessions.remove(&session.id) {
+                Some(previous) => {
+                    set_playback_metrics(session, &previous, &current, metrics);
+
+                    if previous.item_id == current.item_id {
+                        current.playing_since = previous.playing_since;
+                        current.update_stall_state(&previous);
+
+                        if current.stalled && !previous.stalled {
+                            metrics.jellyfin_playback_stalls.with_label_values(&[&apply("client", &session.client), play_method(session)]).inc();
+                        }
+
+                        if let Some(speed) = transcode_speed(session, &previous, &current) {
+                            transcode_speeds.push((session, speed));
+                        }
+                    } else {
+                        playback_stopped(&previous, now, metrics);
+                        playback_started(&current, metrics);
+                    }
+                }
+                None if self.initialized => playback_started(&current, metrics),
+                // Its start was not seen, so neither its stop nor its duration is counted
+                None => current.playing_since = None,
+            }
+
+            tracked.insert(session.id.clone(), current);
+        }
+
+        // Everything left has vanished since the last collection
+        for previous in self.sessions.values() {
+            playback_stopped(previous, now, metrics);
+        }
+
+        metrics.jellyfin_playback_stalled_sessions.reset();
+        for session in sessions.iter().filter(|it| tracked.get(&it.id).is_some_and(|it| it.stalled)) {
+            metrics.jellyfin_playback_stalled_sessions.with_label_values(&[&apply("client", &session.client), play_method(session)]).inc();
+        }
+
+        set_transcode_speed_metrics(&transcode_speeds, metrics);
+        self.update_peaks(sessions, now, metrics);
+
+        self.sessions = tracked;
+        self.initialized = true;
+    }
+
+    fn update_peaks(&mut self, sessions: &[Session], now: Instant, metrics: &Metrics) {
+        let playing = sessions.iter().filter(|it| it.now_playing_item.is_some());
+
+        self.samples.push_back(ConcurrencySample {
+            seen_at: now,
+            active: playing.clone().count() as i64,
+            transcoding: playing.clone().filter(|it| it.play_state.play_method.as_deref() == Some("Transcode")).count() as i64,
+            remote: playing.filter(|it| it.network == NetworkClass::Remote).count() as i64,
+        });
+
+        while self.samples.front().is_some_and(|it| now.duration_since(it.seen_at) > self.peak_window) {
+            self.samples.pop_front();
+        }
+
+        metrics.jellyfin_sessions_peak_active.set(self.samples.iter().map(|it| it.active).max().unwrap_or(0));
+        metrics.jellyfin_sessions_peak_transcoding.set(self.samples.iter().map(|it| it.transcoding).max().unwrap_or(0));
+        metrics.jellyfin_sessions_peak_remote.set(self.samples.iter().map(|it| it.remote).max().unwrap_or(0));
+    }
+}
+
+fn play_method(session: &Session) -> &str {
+    session.play_state.play_method.as_deref().unwrap_or("unknown")
+}
+
+fn playback_started(current: &TrackedSession, metrics: &Metrics) {
+    if current.item_id.is_some() {
+        metrics.jellyfin_playback_starts.with_label_values(&[&apply("user_name", &current.user_name), &apply("client", &current.client)]).inc();
+    }
+}
+
+fn playback_stopped(previous: &TrackedSession, now: Instant, metrics: &Metrics) {
+    let Some(playing_since) = previous.playing_since else { return };
+
+    metrics.jellyfin_playback_stops.with_label_values(&[&apply("user_name", &previous.user_name), &apply("client", &previous.client)]).inc();
+    metrics.jellyfin_playback_duration_seconds.observe(now.duration_since(playing_since).as_secs_f64());
+}
+
+fn set_playback_metrics(session: &Session, previous: &TrackedSession, current: &TrackedSession, metrics: &Metrics) {
+    let Some(item) = &session.now_playing_item else { return };
+    let (Some(previous_ticks), Some(current_ticks)) = (previous.position_ticks, current.position_ticks) else { return };
+
+    if current.item_id != previous.item_id || current.is_paused || current_ticks <= previous_ticks {
+        return;
+    }
+
+    let elapsed = current.seen_at.duration_since(previous.seen_at).as_secs_f64();
+    let watched = ((current_ticks - previous_ticks) as f64 / TICKS_PER_SECOND as f64).min(elapsed * MAX_PLAYBACK_SPEED);
+
+    metrics
+        .jellyfin_playback_seconds
+        .with_label_values(&[&apply("user_name", &session.user_name), &apply("client", &session.client), item.type_name(), play_method(session)])
+        .inc_by(watched);
+}
+
+/// The effective transcode speed as a multiple of realtime: How many seconds of the item were transcoded per second of wall-clock time.
+/// A value below 1 means the transcode does not keep up with the playback.
+fn transcode_speed(session: &Session, previous: &TrackedSession, current: &TrackedSession) -> Option<f64> {
+    let run_time_ticks = session.now_playing_item.as_ref()?.run_time_ticks()?;
+    let (previous_completion, current_completion) = (previous.transcode_completion?, current.transcode_completion?);
+
+    // A finished or restarted (e.g. after seeking) transcode says nothing about the speed
+    if current_completion <= previous_completion || current_completion >= 100.0 {
+        return None;
+    }
+
+    let transcoded = (current_completion - previous_completion) / 100.0 * run_time_ticks as f64 / TICKS_PER_SECOND as f64;
+    Some(transcoded / current.seen_at.duration_since(previous.seen_at).as_secs_f64())
+}
+
+fn set_transcode_speed_metrics(speeds: &[(&Session, f64)], metrics: &Metrics) {
+    metrics.jellyfin_transcode_speed.reset();
+    metrics.jellyfin_transcode_speed_average.reset();
+
+    let mut by_hardware = HashMap::<&str, (f64, usize)>::new();
+
+    for (session, speed) in speeds {
+        let hardware = session.transcoding_info.as_ref().and_then(|it| it.hardware_acceleration_type.as_deref()).unwrap_or("none");
+        metrics.jellyfin_transcode_speed.with_label_values(&[&session.id, &apply("user_name", &session.user_name), &apply("client", &session.client), hardware]).set(*speed);
+
+        let (sum, count) = by_hardware.entry(hardware).or_default();
+        *sum += speed;
+        *count += 1;
+    }
+
+    for (hardware, (sum, count)) in by_hardware {
+        metrics.jellyfin_transcode_speed_average.with_label_values(&[hardware]).set(sum / count as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{Item, PlayState, test_metrics};
+
+    // Each test uses its own client, as the metrics are shared by all tests
+    fn session(client: &str, playing: bool, is_paused: bool, position_seconds: i64) -> Session {
+        Session {
+            id: client.to_string(),
+            client: client.to_string(),
+            now_playing_item: playing.then_some(Item::Other),
+            play_state: PlayState { position_ticks: playing.then_some(position_seconds * TICKS_PER_SECOND), is_paused, ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    fn stalls(metrics: &Metrics, client: &str) -> u64 {
+        metrics.jellyfin_playback_stalls.with_label_values(&[client, "unknown"]).get()
+    }
+
+    /// Updates the tracker with the session at 0s, 15s and 31s, the positions in seconds
+    fn track(metrics: &Metrics, client: &str, playing: bool, is_paused: bool, positions: [i64; 3]) -> bool {
+        let mut tracker = SessionTracker::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        for (seconds, position) in [0, 15, 31].into_iter().zip(positions) {
+            tracker.update(&[session(client, playing, is_paused, position)], start + Duration::from_secs(seconds), metrics);
+        }
+
+        tracker.sessions[client].stalled
+    }
+
+    fn stops(metrics: &Metrics, client: &str) -> u64 {
+        metrics.jellyfin_playback_stops.with_label_values(&["", client]).get()
+    }
+
+    #[test]
+    fn playback_running_at_startup_is_not_counted() {
+        let metrics = test_metrics();
+        let mut tracker = SessionTracker::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        tracker.update(&[session("tracker-startup", true, false, 60)], start, &metrics);
+        assert_eq!(tracker.sessions["tracker-startup"].playing_since, None);
+
+        tracker.update(&[session("tracker-startup", false, false, 0)], start + Duration::from_secs(5), &metrics);
+        assert_eq!(stops(&metrics, "tracker-startup"), 0);
+
+        // The next playback is seen from its start
+        tracker.update(&[session("tracker-startup", true, false, 0)], start + Duration::from_secs(10), &metrics);
+        tracker.update(&[session("tracker-startup", false, false, 0)], start + Duration::from_secs(15), &metrics);
+        assert_eq!(stops(&metrics, "tracker-startup"), 1);
+        assert_eq!(metrics.jellyfin_playback_starts.with_label_values(&["", "tracker-startup"]).get(), 1);
+    }
+
+    #[test]
+    fn idle_sessions_are_not_stalled() {
+        let metrics = test_metrics();
+
+        assert!(!track(&metrics, "tracker-idle", false, false, [0, 0, 0]));
+        assert_eq!(stalls(&metrics, "tracker-idle"), 0);
+    }
+
+    #[test]
+    fn paused_sessions_are_not_stalled() {
+        let metrics = test_metrics();
+
+        assert!(!track(&metrics, "tracker-paused", true, true, [60, 60, 60]));
+        assert_eq!(stalls(&metrics, "tracker-paused"), 0);
+    }
+
+    #[test]
+    fn playing_sessions_stall_without_progress() {
+        let metrics = test_metrics();
+
+        assert!(!track(&metrics, "tracker-playing", true, false, [60, 75, 91]));
+        assert_eq!(stalls(&metrics, "tracker-playing"), 0);
+
+        assert!(track(&metrics, "tracker-stalled", true, false, [60, 62, 64]));
+        assert_eq!(stalls(&metrics, "tracker-stalled"), 1);
+    }
+}
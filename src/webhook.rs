@@ -0,0 +1,104 @@
+use crate::cli::Cli;
+use crate::metrics::Metrics;
+use crate::privacy::apply;
+use axum::http::{HeaderMap, StatusCode};
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+pub const SECRET_HEADER: &str = "X-Webhook-Secret";
+
+// The body is chosen by whoever can reach the port, so every label is bounded. Unknown types and excess values are exported as `other`.
+const OTHER: &str = "other";
+const MAX_LABEL_VALUES: usize = 100;
+
+// The `NotificationType` values of the Jellyfin Webhook plugin
+const NOTIFICATION_TYPES: &[&str] = &[
+    "Generic", "ItemAdded", "ItemDeleted", "PlaybackStart", "PlaybackProgress", "PlaybackStop", "SubtitleDownloadFailure", "AuthenticationFailure", "AuthenticationSuccess", "SessionStart", "PendingRestart",
+    "TaskCompleted", "PluginInstallationCancelled", "PluginInstallationFailed", "PluginInstalled", "PluginInstalling", "PluginUninstalled", "PluginUpdated", "UserCreated", "UserDeleted", "UserLockedOut",
+    "UserPasswordChanged", "UserUpdated", "UserDataSaved",
+];
+
+// `BaseItemKind` of Jellyfin
+const ITEM_TYPES: &[&str] = &[
+    "AggregateFolder", "Audio", "AudioBook", "BasePluginFolder", "Book", "BoxSet", "Channel", "ChannelFolderItem", "CollectionFolder", "Episode", "Folder", "Genre", "ManualPlaylistsFolder", "Movie", "LiveTvChannel",
+    "LiveTvProgram", "MusicAlbum", "MusicArtist", "MusicGenre", "MusicVideo", "Person", "Photo", "PhotoAlbum", "Playlist", "PlaylistsFolder", "Program", "Recording", "Season", "Series", "Studio", "Trailer",
+    "TvChannel", "TvProgram", "UserRootFolder", "UserView", "Video", "Year",
+];
+
+static USERS: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+static CLIENTS: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// A notification of the Jellyfin Webhook plugin, sent by a "Generic" destination.
+///
+/// The plugin renders a user-defined template, so only `NotificationType` is required. A template covering all labels looks like
+/// `{"NotificationType": "{{NotificationType}}", "NotificationUsername": "{{NotificationUsername}}", "ItemType": "{{ItemType}}", "ClientName": "{{ClientName}}"}`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct WebhookEvent {
+    pub notification_type: String,
+    // `AuthenticationFailure` only knows the name that was tried to log in with
+    #[serde(alias = "Username")]
+    pub notification_username: Option<String>,
+    pub item_type: Option<String>,
+    pub client_name: Option<String>,
+}
+
+pub fn handle_webhook(cli: &Cli, metrics: &Metrics, headers: &HeaderMap, body: &[u8]) -> StatusCode {
+    if let Some(secret) = &cli.jellyfin_exporter_webhook_secret {
+        let given = headers.get(SECRET_HEADER).map(|it| it.as_bytes()).unwrap_or_default();
+
+        if !constant_time_eq(given, secret.expose().as_bytes()) {
+            warn!("Rejected a webhook request with a missing or wrong secret");
+            metrics.jellyfin_exporter_webhook_rejected.with_label_values(&["unauthorized"]).inc();
+            return StatusCode::UNAUTHORIZED;
+        }
+    }
+
+    let event = match serde_json::from_slice::<WebhookEvent>(body) {
+        Ok(it) => it,
+        Err(e) => {
+            warn!("Could not decode webhook event: {:?}", e);
+            metrics.jellyfin_exporter_webhook_rejected.with_label_values(&["invalid_payload"]).inc();
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+
+    metrics
+        .jellyfin_webhook_events
+        .with_label_values(&[
+            known(NOTIFICATION_TYPES, &event.notification_type),
+            &apply("user_name", &bounded(&USERS, event.notification_username.as_deref().unwrap_or_default())),
+            known(ITEM_TYPES, event.item_type.as_deref().unwrap_or_default()),
+            &apply("client", &bounded(&CLIENTS, event.client_name.as_deref().unwrap_or_default())),
+        ])
+        .inc();
+
+    StatusCode::NO_CONTENT
+}
+
+fn known<'a>(values: &[&'static str], value: &'a str) -> &'a str {
+    if value.is_empty() || values.contains(&value) { value } else { OTHER }
+}
+
+// Users and clients are not known upfront, so the first `MAX_LABEL_VALUES` distinct values are kept
+fn bounded(seen: &Mutex<Option<HashSet<String>>>, value: &str) -> String {
+    let mut seen = seen.lock().unwrap();
+    let seen = seen.get_or_insert_default();
+
+    if value.is_empty() || seen.contains(value) {
+        return value.to_string();
+    }
+    if seen.len() >= MAX_LABEL_VALUES {
+        return OTHER.to_string();
+    }
+
+    seen.insert(value.to_string());
+    value.to_string()
+}
+
+// Comparing byte by byte would leak the length of the matching prefix through the response time
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
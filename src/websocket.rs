@@ -0,0 +1,203 @@
+use crate::cli::Cli;
+use crate::http_client::{DEVICE_ID, endpoint, headers, tls_connector};
+use crate::metrics::{Metrics, Session};
+use crate::network::NetworkClassifier;
+use crate::tracker::SessionTracker;
+use futures::{SinkExt, StreamExt};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::Connector;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use url::Url;
+
+// Jellyfin sends the sessions in this interval (in ms) after subscribing with `SessionsStart`
+const SESSIONS_INTERVAL: &str = "0,1500";
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// The sessions as last pushed by the Jellyfin WebSocket. `None` while the socket is not connected, the collector then falls back to polling.
+#[derive(Default)]
+pub struct SocketState {
+    pub sessions: Mutex<Option<Vec<Session>>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct SocketMessage {
+    message_type: String,
+    data: Option<serde_json::Value>,
+}
+
+pub fn socket_url(cli: &Cli) -> Url {
+    // The API key is sent in the Authorization header instead of the `api_key` parameter, which would end up in access logs
+    let mut url = endpoint(cli, "/socket", &[("deviceId", DEVICE_ID)]);
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+
+    url.set_scheme(scheme).unwrap();
+    url
+}
+
+/// Keeps the session state up to date by subscribing to the Jellyfin WebSocket. Reconnects with an exponential backoff.
+pub async fn run_socket(cli: Cli, network: NetworkClassifier, state: Arc<SocketState>, tracker: Arc<Mutex<SessionTracker>>, metrics: Metrics) {
+    let url = socket_url(&cli);
+    let connector = match tls_connector(&cli) {
+        Ok(it) => Connector::NativeTls(it),
+        Err(e) => {
+            error!("Could not set up TLS for the Jellyfin WebSocket: {}", e);
+            return;
+        }
+    };
+    let mut delay = Duration::from_secs(1);
+
+    loop {
+        let s = Instant::now();
+        if let Err(e) = handle_socket(&cli, &url, &connector, &network, &state, &tracker, &metrics).await {
+            warn!("Jellyfin WebSocket disconnected: {:?}", e);
+        }
+
+        *state.sessions.lock().unwrap() = None;
+        metrics.jellyfin_exporter_websocket_connected.set(0);
+
+        // A connection that was up for a while is not a failure, so start over with the backoff
+        if s.elapsed() > MAX_RECONNECT_DELAY {
+            delay = Duration::from_secs(1);
+        }
+
+        debug!("Reconnecting to the Jellyfin WebSocket in {:?}", delay);
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        metrics.jellyfin_exporter_websocket_reconnects.inc();
+    }
+}
+
+async fn handle_socket(cli: &Cli, url: &Url, connector: &Connector, network: &NetworkClassifier, state: &SocketState, tracker: &Mutex<SessionTracker>, metrics: &Metrics) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let mut request = url.as_str().into_client_request()?;
+    request.headers_mut().extend(headers(cli));
+
+    let (mut socket, _) = tokio_tungstenite::connect_async_tls_with_config(request, None, false, Some(connector.clone())).await?;
+
+    socket.send(Message::text(format!(r#"{{"MessageType":"SessionsStart","Data":"{SESSIONS_INTERVAL}"}}"#))).await?;
+    metrics.jellyfin_exporter_websocket_connected.set(1);
+    info!("Connected to the Jellyfin WebSocket");
+
+    let mut keep_alive = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+
+    loop {
+        let message = tokio::select! {
+            _ = keep_alive.tick() => {
+                socket.send(Message::text(r#"{"MessageType":"KeepAlive"}"#)).await?;
+                continue;
+            }
+            it = socket.next() => it,
+        };
+
+        let text = match message {
+            Some(Ok(Message::Text(it))) => it,
+            Some(Ok(Message::Close(_))) | None => return Ok(()),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e),
+        };
+
+        let message = match serde_json::from_str::<SocketMessage>(&text) {
+            Ok(it) => it,
+            Err(e) => {
+                warn!("Could not decode WebSocket message: {:?}", e);
+                continue;
+            }
+        };
+
+        if message.message_type != "Sessions" {
+            continue;
+        }
+
+        match serde_json::from_value::<Vec<Session>>(message.data.unwrap_or_default()) {
+            Ok(mut sessions) => {
+                network.classify_sessions(&mut sessions);
+                tracker.lock().unwrap().update(&sessions, Instant::now(), metrics);
+                *state.sessions.lock().unwrap() = Some(sessions);
+            }
+            Err(e) => warn!("Could not decode sessions from the WebSocket: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::test_metrics;
+    use clap::Parser;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::WebSocketStream;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn accept(listener: &TcpListener) -> WebSocketStream<TcpStream> {
+        let (stream, _) = tokio::time::timeout(TIMEOUT, listener.accept()).await.expect("the exporter did not connect").unwrap();
+        tokio_tungstenite::accept_async(stream).await.unwrap()
+    }
+
+    async fn receive(socket: &mut WebSocketStream<TcpStream>) -> serde_json::Value {
+        let message = tokio::time::timeout(TIMEOUT, socket.next()).await.expect("the exporter sent nothing").unwrap().unwrap();
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    async fn eventually(condition: impl Fn() -> bool) {
+        let s = Instant::now();
+        while !condition() {
+            assert!(s.elapsed() < TIMEOUT, "condition not met within {TIMEOUT:?}");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_the_socket_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let cli = Cli::try_parse_from(["jellyfin-exporter", "--jellyfin-address", &address]).unwrap();
+
+        let metrics = test_metrics();
+        let state = Arc::new(SocketState::default());
+        let tracker = Arc::new(Mutex::new(SessionTracker::new(Duration::from_secs(60))));
+        let task = tokio::spawn(run_socket(cli.clone(), NetworkClassifier::new(&cli, None), state.clone(), tracker.clone(), metrics.clone()));
+
+        let mut socket = accept(&listener).await;
+        assert_eq!(receive(&mut socket).await, serde_json::json!({"MessageType": "SessionsStart", "Data": SESSIONS_INTERVAL}));
+        // The keep-alive interval ticks right away
+        assert_eq!(receive(&mut socket).await, serde_json::json!({"MessageType": "KeepAlive"}));
+        assert_eq!(metrics.jellyfin_exporter_websocket_connected.get(), 1);
+        assert!(state.sessions.lock().unwrap().is_none());
+
+        let sessions = r#"{"MessageType":"Sessions","Data":[{
+            "Id":"s1","UserId":"u1","UserName":"alice","ServerId":"server","IsActive":true,"Client":"Jellyfin Web","DeviceName":"Firefox","DeviceId":"d1",
+            "ApplicationVersion":"10.10.0","RemoteEndPoint":"127.0.0.1","LastActivityDate":"2024-05-06T07:08:09Z","PlayState":{"IsPaused":false,"IsMuted":false},
+            "NowPlayingItem":{"Type":"Folder"}
+        }]}"#;
+        socket.send(Message::text(sessions)).await.unwrap();
+
+        eventually(|| state.sessions.lock().unwrap().is_some()).await;
+        let ids = state.sessions.lock().unwrap().as_ref().unwrap().iter().map(|it| it.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids, ["s1"]);
+        {
+            let tracker = tracker.lock().unwrap();
+            assert!(tracker.initialized);
+            assert!(tracker.sessions.contains_key("s1"));
+        }
+        assert_eq!(tracker.lock().unwrap().samples.back().map(|it| it.active), Some(1));
+
+        // After a disconnect the collector has to poll again, until the exporter reconnected
+        socket.close(None).await.unwrap();
+        drop(socket);
+        eventually(|| state.sessions.lock().unwrap().is_none() && metrics.jellyfin_exporter_websocket_connected.get() == 0).await;
+
+        let mut socket = accept(&listener).await;
+        assert_eq!(receive(&mut socket).await["MessageType"], "SessionsStart");
+        assert_eq!(receive(&mut socket).await["MessageType"], "KeepAlive");
+        assert_eq!(metrics.jellyfin_exporter_websocket_connected.get(), 1);
+        assert_eq!(metrics.jellyfin_exporter_websocket_reconnects.get(), 1);
+
+        task.abort();
+    }
+}